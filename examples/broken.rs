@@ -10,7 +10,7 @@ use std::net::{TcpStream, Shutdown};
 use std::io::Write;
 use std::error::Error;
 use text_io::read;
-use shared::{Msg, ADDR};
+use shared::{Msg, Reply, ADDR};
 use srve::Client;
 
 /// Stablish a connection and send an incomplete message.
@@ -56,7 +56,7 @@ fn pk_bad() -> Result<(), Box<dyn Error>>{
 /// Stablish a connection, sends a good pk, but then closes before receiving the
 /// server response-
 fn pk_no_recv() -> Result<(), Box<dyn Error>>{
-    let mut c = Client::connect(ADDR)?;
+    let mut c = Client::<Msg, Reply>::connect(ADDR)?;
     c.send(Msg::Print)?;
     c.close()?;
     Ok(())
@@ -0,0 +1,53 @@
+//! Typed client and server generated from a trait, run with `--features derive`.
+extern crate srve;
+
+use srve::{service, Server};
+use std::thread;
+use std::time::Duration;
+
+const ADDR: &str = "127.0.0.1:6937";
+
+/// A calculator kept by the server, one per connection.
+#[service]
+pub trait Calc {
+    /// Add to the value.
+    fn add(&mut self, x: i32);
+    /// Subtract from the value.
+    fn sub(&mut self, x: i32);
+    /// Get the current value.
+    fn print(&self) -> i32;
+}
+
+#[derive(Default)]
+struct State {
+    value: i32,
+}
+
+impl Calc for State {
+    fn add(&mut self, x: i32) {
+        self.value += x;
+    }
+
+    fn sub(&mut self, x: i32) {
+        self.value -= x;
+    }
+
+    fn print(&self) -> i32 {
+        self.value
+    }
+}
+
+fn main() {
+    let server = Server::<State, CalcRequest, CalcResponse>::bind(ADDR)
+        .expect("Failed to bind server")
+        // runs the trait methods on the connection state and replies
+        .on_request(calc_dispatch::<State>);
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = CalcClient::connect(ADDR).expect("Failed to connect");
+    client.add(5).unwrap();
+    client.sub(2).unwrap();
+    println!("server: {}", client.print().unwrap());
+    client.close().unwrap();
+}
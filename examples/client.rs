@@ -6,12 +6,24 @@ extern crate srve;
 mod shared;
 use text_io::read;
 use srve::Client;
-use shared::{Msg, ADDR};
+use shared::{Msg, Reply, ADDR};
 
-fn main() {
+/// Connects over tcp, or over a websocket when run as `client ws` (needs the `websocket`
+/// feature).
+fn connect() -> Client<Msg, Reply> {
+    #[cfg(feature = "websocket")]
+    if std::env::args().nth(1).as_deref() == Some("ws") {
+        println!("connecting to {}", shared::WS_URL);
+        return Client::connect_ws(shared::WS_URL)
+            .expect("Failed to connect");
+    }
     println!("connecting to {}", ADDR);
-    let mut client: Client<Msg> = Client::connect(ADDR)
-        .expect("Failed to connect");
+    Client::connect(ADDR)
+        .expect("Failed to connect")
+}
+
+fn main() {
+    let mut client = connect();
 
     println!(" ...::: COMMANDS :::... ");
     println!();
@@ -26,12 +38,14 @@ fn main() {
         match s.as_str() {
             "add" => {
                 let x: i32 = read!();
-                client.send(Msg::Add(x)).unwrap();
-                match client.recv() {
+                match client.request(Msg::Add(x)) {
                     Err(e) => panic!("err: {}", e),
-                    Ok(Msg::Ok) => {
+                    Ok(Reply::Ok) => {
                         println!("server: ok");
                     }
+                    Ok(Reply::Error(e)) => {
+                        eprintln!("server: error: {}", e);
+                    }
                     _ => {
                         eprintln!("server: unexpected response");
                     }
@@ -39,24 +53,28 @@ fn main() {
             }
             "sub" => {
                 let x: i32 = read!();
-                client.send(Msg::Sub(x)).unwrap();
-                match client.recv() {
+                match client.request(Msg::Sub(x)) {
                     Err(e) => panic!("err: {}", e),
-                    Ok(Msg::Ok) => {
+                    Ok(Reply::Ok) => {
                         println!("server: ok");
                     }
+                    Ok(Reply::Error(e)) => {
+                        eprintln!("server: error: {}", e);
+                    }
                     _ => {
                         eprintln!("server: unexpected response");
                     }
                 }
             }
             "print" => {
-                client.send(Msg::Print).unwrap();
-                match client.recv() {
+                match client.request(Msg::Print) {
                     Err(e) => panic!("err: {}", e),
-                    Ok(Msg::Value(x)) => {
+                    Ok(Reply::Value(x)) => {
                         println!("server: {}", x);
                     }
+                    Ok(Reply::Error(e)) => {
+                        eprintln!("server: error: {}", e);
+                    }
                     _ => {
                         eprintln!("server: unexpected response");
                     }
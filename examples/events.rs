@@ -0,0 +1,63 @@
+//! Event driven client, replies are printed as they arrive from the server.
+#[macro_use]
+extern crate serde_derive;
+extern crate text_io;
+extern crate srve;
+
+mod shared;
+use text_io::read;
+use srve::{Client, EventClient};
+use shared::{Msg, Reply, ADDR};
+
+/// Connects over tcp, or over a websocket when run as `events ws` (needs the `websocket`
+/// feature).
+fn connect() -> Client<Msg, Reply> {
+    #[cfg(feature = "websocket")]
+    if std::env::args().nth(1).as_deref() == Some("ws") {
+        println!("connecting to {}", shared::WS_URL);
+        return Client::connect_ws(shared::WS_URL)
+            .expect("Failed to connect");
+    }
+    println!("connecting to {}", ADDR);
+    Client::connect(ADDR)
+        .expect("Failed to connect")
+}
+
+fn main() {
+    let client = EventClient::from_client(connect())
+        // runs on the background thread, for every message from the server
+        .on_message(|_client, msg| {
+            println!("server: {:?}", msg);
+        })
+        .on_closed(|_client| {
+            println!("server closed the connection");
+        })
+        .on_error(|_client, e| {
+            eprintln!("err: {}", e);
+        })
+        .start()
+        .expect("Failed to start client");
+
+    println!(" ...::: COMMANDS :::... ");
+    println!();
+    println!("> add x");
+    println!("> sub x");
+    println!("> print");
+    println!("> bye");
+    println!();
+
+    loop {
+        let s: String = read!();
+        let msg = match s.as_str() {
+            "add" => Msg::Add(read!()),
+            "sub" => Msg::Sub(read!()),
+            "print" => Msg::Print,
+            "bye" => {
+                client.close().unwrap();
+                break;
+            }
+            _ => continue,
+        };
+        client.send(msg).unwrap();
+    }
+}
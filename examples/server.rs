@@ -5,55 +5,69 @@ extern crate simple_logger;
 extern crate log;
 
 mod shared;
-use srve::Server;
-use shared::{State, Msg, ADDR};
-use log::{info, trace, warn, LevelFilter};
+use srve::{Conn, IntoResponse, Server};
+use shared::{State, Msg, Reply, ADDR};
+use log::{info, trace, LevelFilter};
+
+/// Why a message could not be applied.
+enum CalcError {
+    Overflow,
+}
+
+/// Errors are sent back to the client as a response.
+impl IntoResponse<Reply> for CalcError {
+    fn into_response(self) -> Reply {
+        match self {
+            CalcError::Overflow => Reply::Error("overflow".to_string()),
+        }
+    }
+}
+
+/// Apply a message to the connection state, returning the response.
+fn handle(conn: &mut Conn<State, Reply>, msg: Msg) -> Result<Reply, CalcError> {
+    match msg {
+        Msg::Add(x) => {
+            info!("{} :: add {}", conn.addr, x);
+            conn.value = conn.value.checked_add(x).ok_or(CalcError::Overflow)?;
+            Ok(Reply::Ok)
+        }
+        Msg::Sub(x) => {
+            info!("{} :: sub {}", conn.addr, x);
+            conn.value = conn.value.checked_sub(x).ok_or(CalcError::Overflow)?;
+            Ok(Reply::Ok)
+        }
+        Msg::Print => {
+            info!("{} :: value = {}", conn.addr, conn.value);
+            Ok(Reply::Value(conn.value))
+        }
+    }
+}
 
 fn main() {
     /* select log level for crate */
     simple_logger::SimpleLogger::new()
         .with_module_level("srve", LevelFilter::Off)
+        .with_module_level("tungstenite", LevelFilter::Off)
         .init()
         .unwrap();
 
-    Server::<State,Msg>::bind(ADDR)
-        .expect("Failed to bind server")
+    let server = Server::<State, Msg, Reply>::bind(ADDR)
+        .expect("Failed to bind server");
+    // also accept websocket clients
+    #[cfg(feature = "websocket")]
+    let server = server.bind_ws(shared::WS_ADDR)
+        .expect("Failed to bind websocket server");
+
+    server
         // calback function for new connections
         .on_connection(|_conn| {
             trace!("connection cb");
         })
-        // callback function for new messages
-        .on_message(|conn, msg| {
-            trace!("message cb");
-
-            match msg {
-                Msg::Add(x) => {
-                    info!("{} :: add {}", conn.addr, x);
-                    conn.value += x;
-                    conn.send(Msg::Ok).unwrap_or_else(|_| {
-                        warn!("send failed");
-                    });
-                },
-                Msg::Sub(x) => {
-                    info!("{} :: sub {}", conn.addr, x);
-                    conn.value -= x;
-                    conn.send(Msg::Ok).unwrap_or_else(|_| {
-                        warn!("send failed");
-                    });
-                }
-                Msg::Print => {
-                    info!("{} :: value = {}", conn.addr, conn.value);
-                    conn.send(Msg::Value(conn.value)).unwrap_or_else(|_| {
-                        warn!("send failed");
-                    });
-                }
-                _ => {
-                    warn!("{} :: unexpected message", conn.addr);
-                    conn.send(Msg::Err).unwrap_or_else(|_| {
-                        warn!("send failed");
-                    });
-                }
-            }
+        // callback function for new messages and requests, what it returns is sent
+        // back, as the reply the client waits for in the case of requests
+        .on_respond(|conn, msg| {
+            trace!("respond cb");
+            handle(conn, msg).map(Some)
         })
         // callback function for connection closing
         .on_closed(|_conn| {
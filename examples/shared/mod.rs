@@ -0,0 +1,29 @@
+//! This is the shared definitions for the client and server code.
+#![allow(dead_code)]
+
+/// Sent by the client to the server.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Msg {
+    Add(i32),
+    Sub(i32),
+    Print,
+}
+
+/// Sent by the server back to the client.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    Ok,
+    Value(i32),
+    Error(String),
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    pub value: i32,
+}
+
+pub const ADDR: &str = "127.0.0.1:6935";
+
+pub const WS_ADDR: &str = "127.0.0.1:6936";
+
+pub const WS_URL: &str = "ws://127.0.0.1:6936";
@@ -0,0 +1,219 @@
+use crate::error::Error;
+use crate::handle::ServerHandle;
+use crate::handler::Handler;
+use crate::server::{Conn, ConnId, Request};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Mailboxes of the running actors, by connection.
+type Registry<Req, T> = Arc<Mutex<HashMap<ConnId, Sender<Mail<Req, T>>>>>;
+
+/// Runs the actor of a connection.
+type ActorFn<Req, Resp, T, S> = Arc<dyn Fn(Mailbox<Req, Resp, T, S>) + Send + Sync>;
+
+/// What an actor receives in its mailbox.
+#[derive(Debug)]
+pub enum Mail<Req, T = ()> {
+    /// The client sent a message, or a request to reply to.
+    Message(Option<Request>, Req),
+    /// A timer set with `Mailbox::schedule_after` is due, with its event.
+    Timer(u64),
+    /// The actor of another connection told this one something.
+    Tell(ConnId, T),
+    /// The connection is gone, the actor should return.
+    Closed,
+}
+
+/// Handler driving every connection with an actor of its own, see `Actors::new`.
+pub struct Actors<Req, Resp = Req, T = (), S = ()> {
+    actor: ActorFn<Req, Resp, T, S>,
+    registry: Registry<Req, T>,
+}
+
+/// Everything an actor has of its connection: the mail it receives, and the ways to
+/// answer it or reach other actors.
+pub struct Mailbox<Req, Resp = Req, T = (), S = ()> {
+    id: ConnId,
+    addr: SocketAddr,
+    mail: Receiver<Mail<Req, T>>,
+    handle: ServerHandle<S, Resp>,
+    registry: Registry<Req, T>,
+}
+
+impl<Req, Resp, T, S> Actors<Req, Resp, T, S>
+where
+    Req: Send + 'static,
+    Resp: Serialize + Send + 'static,
+    T: Send + 'static,
+    S: 'static,
+{
+    /// Run the given actor on a thread of its own for every connection.
+    ///
+    /// The actor is written as a sequential loop over its mail, and the connection is
+    /// closed once it returns.
+    ///
+    /// ```ignore
+    /// server.serve(Actors::new(|mut mailbox: Mailbox<Msg>| loop {
+    ///     match mailbox.recv() {
+    ///         Mail::Message(req, msg) => { ... }
+    ///         Mail::Closed => return,
+    ///         _ => {}
+    ///     }
+    /// }));
+    /// ```
+    pub fn new<F>(actor: F) -> Self
+    where
+        F: Fn(Mailbox<Req, Resp, T, S>) + Send + Sync + 'static,
+    {
+        Self {
+            actor: Arc::new(actor),
+            registry: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Hand mail to the actor of a connection, if still running.
+    fn deliver(&self, id: ConnId, mail: Mail<Req, T>) {
+        if let Some(mailbox) = self.registry.lock().unwrap().get(&id) {
+            // the actor already returned otherwise
+            let _ = mailbox.send(mail);
+        }
+    }
+}
+
+impl<Req, Resp, T, S> Handler<S, Req, Resp> for Actors<Req, Resp, T, S>
+where
+    Req: Send + 'static,
+    Resp: Serialize + Send + 'static,
+    T: Send + 'static,
+    S: 'static,
+{
+    fn on_connection(&mut self, conn: &mut Conn<S, Resp>) {
+        let (tx, rx) = mpsc::channel();
+        let id = conn.id();
+        self.registry.lock().unwrap().insert(id, tx);
+        // the mailbox tells its actor once the connection is gone
+        let registry = self.registry.clone();
+        conn.on_drop(move || { registry.lock().unwrap().remove(&id); });
+
+        let mailbox = Mailbox {
+            id,
+            addr: conn.addr,
+            mail: rx,
+            handle: conn.handle(),
+            registry: self.registry.clone(),
+        };
+        let actor = self.actor.clone();
+        #[cfg(feature = "tracing")]
+        let span = conn.span().clone();
+        thread::spawn(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            let handle = mailbox.handle.clone();
+            let registry = mailbox.registry.clone();
+            if panic::catch_unwind(AssertUnwindSafe(|| actor(mailbox))).is_err() {
+                warn!("{} :: actor panicked", id);
+            }
+            // only still registered while the connection is open
+            if registry.lock().unwrap().remove(&id).is_some() {
+                // the server is gone otherwise
+                let _ = handle.execute(move |conns| {
+                    // the connection may have closed in the meantime
+                    if let Some(conn) = conns.iter_mut().find(|conn| conn.id() == id) {
+                        let _ = conn.close();
+                    }
+                });
+            }
+        });
+    }
+
+    fn on_message(&mut self, conn: &mut Conn<S, Resp>, msg: Req) {
+        self.deliver(conn.id(), Mail::Message(None, msg));
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S, Resp>, request: Request, msg: Req) {
+        self.deliver(conn.id(), Mail::Message(Some(request), msg));
+    }
+
+    fn on_timer(&mut self, conn: &mut Conn<S, Resp>, event: u64) {
+        self.deliver(conn.id(), Mail::Timer(event));
+    }
+}
+
+impl<Req, Resp, T, S> Mailbox<Req, Resp, T, S>
+where
+    Resp: Serialize + Send + 'static,
+    S: 'static,
+{
+    /// Connection of this actor.
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    /// Address of the client.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the next mail, which is always `Mail::Closed` once the connection is gone.
+    pub fn recv(&mut self) -> Mail<Req, T> {
+        self.mail.recv().unwrap_or(Mail::Closed)
+    }
+
+    /// Wait up to the given time for the next mail.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<Mail<Req, T>> {
+        match self.mail.recv_timeout(timeout) {
+            Ok(mail) => Some(mail),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => Some(Mail::Closed),
+        }
+    }
+
+    /// Send a message to the client.
+    pub fn send(&self, msg: Resp) -> Result<(), Error> {
+        self.handle.send_to(self.id, msg)
+    }
+
+    /// Reply to a request from the client.
+    pub fn reply(&self, request: &Request, msg: Resp) -> Result<(), Error> {
+        self.respond(Some(request), msg)
+    }
+
+    /// Reply to the request if there is one, or just send the message otherwise.
+    pub fn respond(&self, request: Option<&Request>, msg: Resp) -> Result<(), Error> {
+        let request = request.copied();
+        self.handle.with(self.id, move |conn| { let _ = conn.respond(request.as_ref(), msg); })
+    }
+
+    /// Get `Mail::Timer` with the given event after some time.
+    pub fn schedule_after(&self, after: Duration, event: u64) -> Result<(), Error> {
+        self.handle.with(self.id, move |conn| conn.schedule_after(after, event))
+    }
+
+    /// Tell something to the actor of another connection.
+    pub fn tell(&self, to: ConnId, msg: T) -> Result<(), Error> {
+        match self.registry.lock().unwrap().get(&to) {
+            Some(mailbox) => mailbox.send(Mail::Tell(self.id, msg)).map_err(|_| Error::Closed),
+            None => {
+                debug!("{} :: no actor for {}", self.id, to);
+                Err(Error::Closed)
+            }
+        }
+    }
+
+    /// Connections of the other running actors.
+    pub fn peers(&self) -> Vec<ConnId> {
+        self.registry.lock().unwrap().keys().copied().filter(|&id| id != self.id).collect()
+    }
+
+    /// Close the connection, the actor gets `Mail::Closed` after its pending mail.
+    pub fn close(&self) -> Result<(), Error> {
+        self.handle.close(self.id)
+    }
+}
@@ -1,48 +1,325 @@
-use crate::pk;
+use crate::error::Error;
+use crate::pk::{Header, RecvResult};
+use crate::stream::Stream;
+use crate::trace::TraceId;
+#[cfg(feature = "websocket")]
+use crate::ws::{self, Codec};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::error::Error;
+use log::debug;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::io::{self, ErrorKind};
 use std::marker::PhantomData;
-use std::net::{TcpStream, Shutdown};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
 /// Represents a connection to a server.
-pub struct Client<M>
+///
+/// The client sends `Req` messages, and the server sends `Resp` messages back, which is the
+/// same type unless told otherwise.
+pub struct Client<Req, Resp = Req>
 {
-    /// Type of the communication messages.
-    msg_type: PhantomData<M>,
-    /// Tcp stream to the server.
-    stream: TcpStream,
+    /// Type of the messages sent to the server.
+    msg_type: PhantomData<fn(Req)>,
+    /// Stream to the server.
+    stream: Stream,
+    /// How long `recv` waits for a message before failing.
+    read_timeout: Option<Duration>,
+    /// Id for the next request.
+    next_id: u64,
+    /// Messages received while waiting for a reply, to be returned by `recv`.
+    pending: VecDeque<Resp>,
+    /// Whether to send a new trace id with every message.
+    trace_ids: bool,
 }
 
-impl<M> Client<M>
+/// Configures how a client connects to a server.
+///
+/// ```ignore
+/// let c: Client<Msg> = ClientBuilder::new()
+///     .connect_timeout(Duration::from_secs(2))
+///     .read_timeout(Duration::from_secs(5))
+///     .nodelay(true)
+///     .connect(("localhost", 6935))?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClientBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    trace_ids: bool,
+}
+
+impl ClientBuilder {
+    /// Create a builder with no timeouts, which is what `Client::connect` uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up on each resolved address after this long.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail `recv` with `Error::Timeout` if no message arrives within this long.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up on `send` if the message cannot be written within this long.
+    ///
+    /// Part of the message may be written by then, so the connection is shut down, and the
+    /// send fails with `Error::Io` rather than `Error::Timeout`, as it cannot be retried.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Set TCP_NODELAY, sending small messages right away instead of batching them.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Enable tcp keepalive, probing the server after the connection is idle this long.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+
+    /// Send a new trace id with every message, which the server sends back with its replies,
+    /// see `Request::trace`.
+    ///
+    /// With the `tracing` feature, the id is logged when sending the message, and recorded in
+    /// the span handling it on the server, so their logs can be joined.
+    pub fn trace_ids(mut self, trace_ids: bool) -> Self {
+        self.trace_ids = trace_ids;
+        self
+    }
+
+    /// Connect to a server, trying each address it resolves to in order.
+    pub fn connect<Req, Resp, A>(&self, addr: A) -> Result<Client<Req, Resp>, Error>
+    where
+        Req: Serialize + Debug,
+        Resp: DeserializeOwned + Debug,
+        A: ToSocketAddrs,
+    {
+        let stream = self.open(addr)?;
+        Ok(Client {
+            msg_type: PhantomData,
+            stream: Stream::tcp(stream),
+            read_timeout: self.read_timeout,
+            next_id: 1,
+            pending: VecDeque::new(),
+            trace_ids: self.trace_ids,
+        })
+    }
+
+    /// Connect to a WebSocket server by its url, e.g. `ws://127.0.0.1:6936`.
+    ///
+    /// There is no TLS support, so `wss://` urls fail with `Error::Other`.
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws<Req, Resp>(&self, url: &str) -> Result<Client<Req, Resp>, Error>
+    where
+        Req: Serialize + Debug,
+        Resp: DeserializeOwned + Debug,
+    {
+        self.connect_ws_with(url, Codec::Bincode)
+    }
+
+    /// Same as `connect_ws`, but encoding messages with the given codec, which must match
+    /// the one used by the server.
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws_with<Req, Resp>(&self, url: &str, codec: Codec)
+        -> Result<Client<Req, Resp>, Error>
+    where
+        Req: Serialize + Debug,
+        Resp: DeserializeOwned + Debug,
+    {
+        let ws = ws::connect(url, |addr| self.open(addr))?;
+        Ok(Client {
+            msg_type: PhantomData,
+            stream: Stream::Ws(ws, codec),
+            read_timeout: self.read_timeout,
+            next_id: 1,
+            pending: VecDeque::new(),
+            trace_ids: self.trace_ids,
+        })
+    }
+
+    /// Open a configured tcp stream to the first address that accepts us.
+    fn open<A>(&self, addr: A) -> Result<TcpStream, Error>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            let res = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match res {
+                Ok(stream) => return self.configure(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no addresses to connect to"))
+            .into())
+    }
+
+    /// Apply the socket options to a freshly connected stream.
+    fn configure(&self, stream: TcpStream) -> Result<TcpStream, Error> {
+        stream.set_write_timeout(self.write_timeout)?;
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(stream)
+    }
+}
+
+impl<Req, Resp> Client<Req, Resp>
 where
-    M: Serialize + DeserializeOwned + Debug
+    Req: Serialize + Debug,
+    Resp: DeserializeOwned + Debug,
 {
     /// Create a new client by connecting to a server by its address.
-    pub fn connect(addr: &str) -> Result<Self, Box<dyn Error>> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(Self {
-            msg_type: PhantomData,
-            stream
-        })
+    pub fn connect<A>(addr: A) -> Result<Self, Error>
+    where
+        A: ToSocketAddrs,
+    {
+        ClientBuilder::new().connect(addr)
+    }
+
+    /// Create a new client by connecting to a WebSocket server by its url, e.g.
+    /// `ws://127.0.0.1:6936`.
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws(url: &str) -> Result<Self, Error> {
+        ClientBuilder::new().connect_ws(url)
+    }
+
+    /// Same as `connect_ws`, but encoding messages with the given codec, which must match
+    /// the one used by the server.
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws_with(url: &str, codec: Codec) -> Result<Self, Error> {
+        ClientBuilder::new().connect_ws_with(url, codec)
     }
 
     /// Send a message to the server.
-    pub fn send(&mut self, msg: M) -> Result<(), Box<dyn Error>> {
-        pk::send(msg, &mut self.stream)?;
+    pub fn send(&mut self, msg: Req) -> Result<(), Error> {
+        self.stream.send(header(None, self.trace_ids), msg)?;
         Ok(())
     }
 
+    /// Send a message to the server, without giving it up.
+    pub(crate) fn send_ref(&mut self, msg: &Req) -> Result<(), Error> {
+        self.stream.send(header(None, self.trace_ids), msg)
+    }
+
+    /// Send a request to the server and wait for its reply (blocks).
+    ///
+    /// Other messages the server sends in the meantime are kept, and returned by the next
+    /// calls to `recv`. If the client was built with a read timeout, fails with
+    /// `Error::Timeout` once it elapses.
+    pub fn request(&mut self, msg: Req) -> Result<Resp, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.send(header(Some(id), self.trace_ids), msg)?;
+
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let (header, msg) = self.recv_frame(deadline)?;
+            match header.reply_to {
+                Some(reply_to) if reply_to == id => return Ok(msg),
+                Some(reply_to) => debug!("dropping late reply to request {}", reply_to),
+                None => self.pending.push_back(msg),
+            }
+        }
+    }
+
     /// Receive a message from the server (blocks).
-    pub fn recv(&mut self) -> Result<M, Box<dyn Error>> {
-        let msg = pk::recv(&mut self.stream)?;
-        Ok(msg)
+    ///
+    /// If the client was built with a read timeout, fails with `Error::Timeout` once it
+    /// elapses.
+    pub fn recv(&mut self) -> Result<Resp, Error> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg);
+        }
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            match self.recv_frame(deadline)? {
+                (Header { reply_to: Some(reply_to), .. }, _) => {
+                    debug!("dropping late reply to request {}", reply_to);
+                }
+                (_, msg) => return Ok(msg),
+            }
+        }
+    }
+
+    /// Receive a message from the server if one is available, returns immediately.
+    ///
+    /// Partially received messages are buffered until the rest of them arrives, so this
+    /// never blocks in the middle of a message.
+    pub fn try_recv(&mut self) -> Result<RecvResult<Resp>, Error> {
+        self.recv_timeout(Duration::ZERO)
+    }
+
+    /// Receive a message from the server, blocking at most for the given duration.
+    ///
+    /// Returns `RecvResult::None` if no complete message arrived in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<RecvResult<Resp>, Error> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(RecvResult::Some(msg));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.stream.recv_timeout(remaining)? {
+                RecvResult::Some((Header { reply_to: Some(reply_to), .. }, _)) => {
+                    debug!("dropping late reply to request {}", reply_to);
+                }
+                res => return Ok(res.map(|(_, msg)| msg)),
+            }
+        }
+    }
+
+    /// Receive the next frame, failing with `Error::Timeout` once the deadline passes.
+    fn recv_frame(&mut self, deadline: Option<Instant>) -> Result<(Header, Resp), Error> {
+        match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                self.stream.recv_timeout(remaining)?.into_result()
+            }
+            None => self.stream.recv(None),
+        }
+    }
+
+    /// Give up the stream to the server, along with whether to send trace ids.
+    pub(crate) fn into_parts(self) -> (Stream, bool) {
+        (self.stream, self.trace_ids)
     }
 
     /// Closes the connection to the server.
-    pub fn close(self) -> Result<(), Box<dyn Error>> {
-        self.stream.shutdown(Shutdown::Both)?;
+    pub fn close(mut self) -> Result<(), Error> {
+        self.stream.shutdown()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Header of a message to send, as a request with the given id if any, and with a new trace
+/// id if enabled.
+pub(crate) fn header(id: Option<u64>, trace_ids: bool) -> Header {
+    let trace = if trace_ids { Some(TraceId::new()) } else { None };
+    #[cfg(feature = "tracing")]
+    if let Some(trace) = trace {
+        tracing::debug!(trace = %trace, request = id, "sending message");
+    }
+    Header { id, reply_to: None, trace: trace.map(|trace| trace.0) }
+}
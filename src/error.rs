@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// Errors that can happen while communicating.
+#[derive(Debug)]
+pub enum Error {
+    /// The operation did not complete in time, e.g. connecting or waiting for a message.
+    Timeout,
+    /// The connection was closed.
+    Closed,
+    /// A message could not be encoded or decoded.
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+    /// The underlying stream failed.
+    Io(io::Error),
+    /// Any other failure, e.g. a rejected websocket handshake.
+    Other(String),
+}
+
+impl Error {
+    /// Whether this error is a timeout.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout)
+    }
+
+    /// Whether a message failed to be decoded, which leaves the connection usable, unlike
+    /// transport errors.
+    pub fn is_decode(&self) -> bool {
+        matches!(self, Error::Codec(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "timed out"),
+            Error::Closed => write!(f, "connection closed"),
+            Error::Codec(e) => write!(f, "codec error: {}", e),
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Codec(e) => Some(&**e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // blocking operations with a timeout report either of these
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Codec(e)
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Codec(Box::new(e))
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        match e {
+            tungstenite::Error::Io(e) => e.into(),
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+                Error::Closed
+            }
+            e => Error::Other(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::client::{self, Client, ClientBuilder};
+use crate::error::Error;
+use crate::pk::RecvResult;
+use crate::stream::Stream;
+use crate::ticket::{InFlight, Ticket};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::net::ToSocketAddrs;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// Callback on the connection.
+type HandleCallback<Req, Resp> = Box<dyn FnMut(&ClientHandle<Req, Resp>) + Send>;
+/// Callback on a connection error.
+type ErrorCallback<Req, Resp> = Box<dyn FnMut(&ClientHandle<Req, Resp>, Error) + Send>;
+/// Callback on a received message.
+type MessageCallback<Req, Resp> = Box<dyn FnMut(&ClientHandle<Req, Resp>, Resp) + Send>;
+
+/// A client driven by callbacks, like the `Server`.
+///
+/// Once started, a background thread receives the messages and runs the callbacks, while
+/// the returned `ClientHandle` sends messages from any thread.
+pub struct EventClient<Req, Resp = Req> {
+    /// Connection to the server, until started.
+    client: Client<Req, Resp>,
+
+    /* connection callbacks */
+    cb_closed: Option<HandleCallback<Req, Resp>>,
+    cb_closed_unexpected: Option<HandleCallback<Req, Resp>>,
+    cb_error: Option<ErrorCallback<Req, Resp>>,
+    cb_message: Option<MessageCallback<Req, Resp>>,
+}
+
+/// Cloneable handle to send messages to the server, from any thread.
+///
+/// Many requests can be in flight at once, from any amount of threads, each reply is
+/// matched to its request no matter the order they arrive in.
+pub struct ClientHandle<Req, Resp = Req> {
+    /// Type of the messages sent to the server.
+    msg_type: PhantomData<fn(Req)>,
+    /// Requests waiting for their reply.
+    in_flight: Arc<InFlight<Resp>>,
+    /// Writing half of the stream to the server.
+    stream: Arc<Mutex<Stream>>,
+    /// Set once the background thread is done.
+    closed: Arc<AtomicBool>,
+    /// Whether to send a new trace id with every message.
+    trace_ids: bool,
+}
+
+impl<Req, Resp> EventClient<Req, Resp>
+where
+    Req: Serialize + Debug + 'static,
+    Resp: DeserializeOwned + Debug + Send + 'static,
+{
+    /// Create a new client by connecting to a server by its address.
+    pub fn connect<A>(addr: A) -> Result<Self, Error>
+    where
+        A: ToSocketAddrs,
+    {
+        Ok(Self::from_client(ClientBuilder::new().connect(addr)?))
+    }
+
+    /// Drive an already connected client with callbacks.
+    pub fn from_client(client: Client<Req, Resp>) -> Self {
+        Self {
+            client,
+            cb_closed: None,
+            cb_closed_unexpected: None,
+            cb_error: None,
+            cb_message: None,
+        }
+    }
+
+    /// Setup a callback for when the server closes the connection.
+    pub fn on_closed<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&ClientHandle<Req, Resp>) + Send + 'static
+    {
+        self.cb_closed = Some(Box::new(cb));
+        self
+    }
+
+    /// Setup a callback for when the connection is closed in the middle of a message.
+    pub fn on_closed_unexpected<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&ClientHandle<Req, Resp>) + Send + 'static
+    {
+        self.cb_closed_unexpected = Some(Box::new(cb));
+        self
+    }
+
+    /// Setup a callback for connection errors.
+    ///
+    /// This callback will be run whenever there is an error attempting to get the next
+    /// message, after which the connection is closed.
+    pub fn on_error<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&ClientHandle<Req, Resp>, Error) + Send + 'static
+    {
+        self.cb_error = Some(Box::new(cb));
+        self
+    }
+
+    /// Setup a callback for each received message.
+    pub fn on_message<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&ClientHandle<Req, Resp>, Resp) + Send + 'static
+    {
+        self.cb_message = Some(Box::new(cb));
+        self
+    }
+
+    /// Start receiving messages on a background thread, running the callbacks there.
+    pub fn start(self) -> Result<ClientHandle<Req, Resp>, Error> {
+        let EventClient {
+            client,
+            mut cb_closed,
+            mut cb_closed_unexpected,
+            mut cb_error,
+            mut cb_message,
+        } = self;
+        let (stream, trace_ids) = client.into_parts();
+        let (mut reader, stream) = stream.split()?;
+        let handle = ClientHandle {
+            msg_type: PhantomData,
+            in_flight: Arc::new(InFlight::new()),
+            stream,
+            closed: Arc::new(AtomicBool::new(false)),
+            trace_ids,
+        };
+
+        let thread_handle = handle.clone();
+        thread::spawn(move || {
+            let handle = thread_handle;
+            // a panicking callback ends the connection, which must still be cleaned up
+            let res = panic::catch_unwind(AssertUnwindSafe(|| loop {
+                let res = reader.recv::<Resp>();
+                // closed by us through a handle, nothing to report
+                if handle.is_closed() {
+                    break;
+                }
+                match res {
+                    Ok(RecvResult::Some((header, msg))) => match header.reply_to {
+                        Some(id) => {
+                            if !handle.in_flight.resolve(id, msg) {
+                                debug!("event client :: dropping late reply to request {}", id);
+                            }
+                        }
+                        None => {
+                            if let Some(cb) = cb_message.as_mut() {
+                                cb(&handle, msg);
+                            }
+                        }
+                    },
+                    Ok(RecvResult::None) => {}
+                    Ok(RecvResult::Closed) => {
+                        info!("event client :: closed");
+                        if let Some(cb) = cb_closed.as_mut() {
+                            cb(&handle);
+                        }
+                        break;
+                    }
+                    Ok(RecvResult::ClosedWrongly) => {
+                        warn!("event client :: closed unexpectedly");
+                        if let Some(cb) = cb_closed_unexpected.as_mut() {
+                            cb(&handle);
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("event client :: error: {}", e);
+                        if let Some(cb) = cb_error.as_mut() {
+                            cb(&handle, e);
+                        }
+                        break;
+                    }
+                }
+            }));
+            if res.is_err() {
+                warn!("event client :: panicked running a callback");
+            }
+            handle.in_flight.close();
+            handle.shutdown();
+        });
+
+        Ok(handle)
+    }
+}
+
+impl<Req, Resp> ClientHandle<Req, Resp>
+where
+    Req: Serialize
+{
+    /// Send a message to the server.
+    pub fn send(&self, msg: Req) -> Result<(), Error> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        let header = client::header(None, self.trace_ids);
+        self.stream.lock().unwrap().send(header, msg)
+    }
+
+    /// Send a request to the server, returns right away with a ticket for its reply.
+    ///
+    /// Replies go to their ticket, never to the message callback.
+    pub fn request(&self, msg: Req) -> Result<Ticket<Resp>, Error> {
+        if self.is_closed() {
+            return Err(Error::Closed);
+        }
+        // registered first, the reply may arrive before `send` returns
+        let ticket = self.in_flight.register()?;
+        let header = client::header(Some(ticket.id()), self.trace_ids);
+        self.stream.lock().unwrap().send(header, msg)?;
+        Ok(ticket)
+    }
+
+    /// Closes the connection to the server, the background thread stops after it.
+    pub fn close(&self) -> Result<(), Error> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.stream.lock().unwrap().shutdown()?;
+        Ok(())
+    }
+}
+
+impl<Req, Resp> ClientHandle<Req, Resp> {
+    /// Whether the connection was closed, by either side.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Mark as closed and shutdown the stream, ignoring failures.
+    fn shutdown(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            // poisoned if a callback panicked sending, the stream is still fine to shutdown
+            let mut stream = self.stream.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = stream.shutdown() {
+                warn!("failed to shutdown stream: {}", e);
+            }
+        }
+    }
+}
+
+impl<Req, Resp> Clone for ClientHandle<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            msg_type: PhantomData,
+            in_flight: self.in_flight.clone(),
+            stream: self.stream.clone(),
+            closed: self.closed.clone(),
+            trace_ids: self.trace_ids,
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::error::Error;
+use crate::pool::Pool;
+use crate::server::{Conn, ConnId, Request};
+use crate::stats::{Counters, ServerStats};
+use log::{debug, warn};
+use serde::Serialize;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// Work sent to the server thread, run on the current connections.
+pub(crate) type Command<S,Resp> = Box<dyn FnOnce(&mut [Conn<S,Resp>]) + Send>;
+
+/// Reaches the connections of a `Server` from other threads, e.g. background jobs.
+///
+/// Obtained with `Server::handle`, it can be cloned and shared freely. Everything is queued
+/// and done on the server thread on its next turn, so sends through a handle report whether
+/// they were queued, not whether they got through: those that fail are logged, and close
+/// their connection if writing them failed, as with `Conn::send`. Once the server is gone,
+/// every method fails with `Error::Closed`.
+pub struct ServerHandle<S, Resp> {
+    commands: Sender<Command<S,Resp>>,
+    /// Runs slow work for the server.
+    pool: Arc<Pool>,
+    /// Counters of the server.
+    counters: Arc<Counters>,
+}
+
+/// Token to respond to a message later, e.g. once slow work is done on another thread.
+///
+/// Obtained with `Conn::defer`. If the connection is gone by the time of the response, the
+/// response is dropped, and if it fails to send, it is logged and the connection is
+/// closed when writing it failed, as with `Conn::send`.
+pub struct Deferred<S, Resp> {
+    handle: ServerHandle<S, Resp>,
+    id: ConnId,
+    request: Option<Request>,
+}
+
+impl<S, Resp> ServerHandle<S, Resp> {
+    pub(crate) fn new(commands: Sender<Command<S,Resp>>, workers: usize) -> Self {
+        Self {
+            commands,
+            pool: Arc::new(Pool::new(workers)),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    pub(crate) fn counters(&self) -> &Arc<Counters> {
+        &self.counters
+    }
+
+    /// Snapshot of the counters of the server.
+    pub fn stats(&self) -> ServerStats {
+        self.counters.snapshot()
+    }
+
+    /// Set the amount of worker threads, before they are started.
+    pub(crate) fn workers(&self, workers: usize) {
+        self.pool.resize(workers);
+    }
+
+    /// Run a job on the worker threads of the server, away from its loop.
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(Box::new(job));
+    }
+}
+
+impl<S, Resp> ServerHandle<S, Resp>
+where
+    S: 'static,
+    Resp: Serialize + Send + 'static,
+{
+    /// Send a message to a connection.
+    pub fn send_to(&self, id: ConnId, msg: Resp) -> Result<(), Error> {
+        self.with(id, move |conn| { let _ = conn.send(msg); })
+    }
+
+    /// Send a message to every connection.
+    pub fn broadcast(&self, msg: Resp) -> Result<(), Error>
+    where
+        Resp: Clone,
+    {
+        self.execute(move |conns| {
+            for conn in conns.iter_mut() {
+                let _ = conn.send(msg.clone());
+            }
+        })
+    }
+
+    /// Close a connection.
+    pub fn close(&self, id: ConnId) -> Result<(), Error> {
+        self.with(id, |conn| { let _ = conn.close(); })
+    }
+
+    /// Run a closure on a connection, on the server thread.
+    pub fn with<F>(&self, id: ConnId, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Conn<S,Resp>) + Send + 'static,
+    {
+        self.execute(move |conns| match conns.iter_mut().find(|conn| conn.id() == id) {
+            Some(conn) => f(conn),
+            None => warn!("{} :: no such connection", id),
+        })
+    }
+
+    /// Run a closure on every current connection, on the server thread.
+    pub fn execute<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [Conn<S,Resp>]) + Send + 'static,
+    {
+        self.commands.send(Box::new(f)).map_err(|_| Error::Closed)
+    }
+}
+
+impl<S, Resp> Clone for ServerHandle<S, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            pool: self.pool.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<S, Resp> Deferred<S, Resp>
+where
+    S: 'static,
+    Resp: Serialize + Send + 'static,
+{
+    pub(crate) fn new(handle: ServerHandle<S, Resp>, id: ConnId, request: Option<Request>) -> Self {
+        Self { handle, id, request }
+    }
+
+    /// Connection to respond to.
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    /// Respond with the given message, as the reply to the request if there was one.
+    pub fn respond(self, msg: Resp) -> Result<(), Error> {
+        let Deferred { handle, id, request } = self;
+        handle.execute(move |conns| {
+            match conns.iter_mut().find(|conn| conn.id() == id && !conn.is_closing()) {
+                Some(conn) => { let _ = conn.respond(request.as_ref(), msg); }
+                None => debug!("{} :: gone, dropping deferred response", id),
+            }
+        })
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::Error;
+use crate::server::{Conn, Request};
+
+/// Handles the events of every connection of a `Server`, see `Server::serve`.
+///
+/// Every method does nothing by default, so only the events of interest need to be
+/// handled. Being a single value, a handler can keep its own state and helper methods,
+/// and wrap other handlers.
+pub trait Handler<S, Req, Resp = Req> {
+    /// Run when the server stablishes a new connection with a client, _before_ any
+    /// messages are received.
+    fn on_connection(&mut self, _conn: &mut Conn<S, Resp>) {}
+
+    /// Run every time a connection sends a new message.
+    fn on_message(&mut self, _conn: &mut Conn<S, Resp>, _msg: Req) {}
+
+    /// Run every time a connection sends a request, to be answered with `Conn::reply`.
+    ///
+    /// By default the request is handed to `on_message`, and is never replied to.
+    fn on_request(&mut self, conn: &mut Conn<S, Resp>, _request: Request, msg: Req) {
+        self.on_message(conn, msg);
+    }
+
+    /// Run when the client terminates the connection.
+    fn on_closed(&mut self, _conn: &mut Conn<S, Resp>) {}
+
+    /// Run when the client closes the connection in the middle of a message.
+    fn on_closed_unexpected(&mut self, _conn: &mut Conn<S, Resp>) {}
+
+    /// Run when the server closed the connection, e.g. with `Conn::close` or
+    /// `ServerHandle::close`, right before it is dropped.
+    fn on_closed_by_server(&mut self, _conn: &mut Conn<S, Resp>) {}
+
+    /// Run when getting the next message fails, e.g. a bad message that fails to be
+    /// deserialized, the connection is closed after it unless the error policy skips the
+    /// bad message. Also run when sending fails, which only closes the connection if
+    /// writing the message failed.
+    fn on_error(&mut self, _conn: &mut Conn<S, Resp>, _e: Error) {}
+
+    /// Run when a timer set with `Conn::schedule_after` is due, with its event.
+    fn on_timer(&mut self, _conn: &mut Conn<S, Resp>, _event: u64) {}
+
+    /// Run when handling a connection panics, with the panic message, after the connection
+    /// is closed. The server keeps serving the other connections.
+    fn on_panic(&mut self, _conn: &mut Conn<S, Resp>, _msg: &str) {}
+}
+
+/// Turns a handler error into the response sent back in its place.
+///
+/// Any response type converts to itself, so handlers can fail with a response directly.
+pub trait IntoResponse<Resp> {
+    fn into_response(self) -> Resp;
+}
+
+impl<Resp> IntoResponse<Resp> for Resp {
+    fn into_response(self) -> Resp {
+        self
+    }
+}
+
+impl<S, Req, Resp, H> Handler<S, Req, Resp> for &mut H
+where
+    H: Handler<S, Req, Resp> + ?Sized,
+{
+    fn on_connection(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_connection(conn)
+    }
+
+    fn on_message(&mut self, conn: &mut Conn<S, Resp>, msg: Req) {
+        (**self).on_message(conn, msg)
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S, Resp>, request: Request, msg: Req) {
+        (**self).on_request(conn, request, msg)
+    }
+
+    fn on_closed(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed(conn)
+    }
+
+    fn on_closed_unexpected(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed_unexpected(conn)
+    }
+
+    fn on_closed_by_server(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed_by_server(conn)
+    }
+
+    fn on_error(&mut self, conn: &mut Conn<S, Resp>, e: Error) {
+        (**self).on_error(conn, e)
+    }
+
+    fn on_timer(&mut self, conn: &mut Conn<S, Resp>, event: u64) {
+        (**self).on_timer(conn, event)
+    }
+
+    fn on_panic(&mut self, conn: &mut Conn<S, Resp>, msg: &str) {
+        (**self).on_panic(conn, msg)
+    }
+}
+
+impl<S, Req, Resp, H> Handler<S, Req, Resp> for Box<H>
+where
+    H: Handler<S, Req, Resp> + ?Sized,
+{
+    fn on_connection(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_connection(conn)
+    }
+
+    fn on_message(&mut self, conn: &mut Conn<S, Resp>, msg: Req) {
+        (**self).on_message(conn, msg)
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S, Resp>, request: Request, msg: Req) {
+        (**self).on_request(conn, request, msg)
+    }
+
+    fn on_closed(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed(conn)
+    }
+
+    fn on_closed_unexpected(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed_unexpected(conn)
+    }
+
+    fn on_closed_by_server(&mut self, conn: &mut Conn<S, Resp>) {
+        (**self).on_closed_by_server(conn)
+    }
+
+    fn on_error(&mut self, conn: &mut Conn<S, Resp>, e: Error) {
+        (**self).on_error(conn, e)
+    }
+
+    fn on_timer(&mut self, conn: &mut Conn<S, Resp>, event: u64) {
+        (**self).on_timer(conn, event)
+    }
+
+    fn on_panic(&mut self, conn: &mut Conn<S, Resp>, msg: &str) {
+        (**self).on_panic(conn, msg)
+    }
+}
@@ -1,11 +1,65 @@
 //! Create simple network wrappers for applications, via TCP sockets.
+//!
+//! With the `websocket` feature, servers can also accept WebSocket connections (e.g. from
+//! browsers) and clients can connect through them.
+//!
+//! With the `prometheus` feature, servers can serve their metrics to be scraped.
+//!
+//! With the `tracing` feature, connections and the messages they handle get spans of their
+//! own, which also hold the trace ids sent by clients built with `ClientBuilder::trace_ids`.
+//!
+//! With the `derive` feature, `#[service]` generates the messages, a typed client and a
+//! server dispatcher from a trait, and `#[derive(Discriminant)]` allows routing messages
+//! by their variant with a `Router`.
 extern crate bincode;
 extern crate log;
 extern crate serde;
 
+mod actor;
 mod client;
+mod error;
+mod events;
+mod handle;
+mod handler;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod middleware;
+mod phase;
 mod pk;
+mod pool;
+mod poll;
+mod reconnect;
+mod router;
 mod server;
+mod stats;
+mod stream;
+mod ticket;
+mod trace;
+#[cfg(feature = "websocket")]
+mod ws;
 
-pub use client::Client;
-pub use server::Server;
\ No newline at end of file
+pub use actor::{Actors, Mail, Mailbox};
+pub use client::{Client, ClientBuilder};
+pub use error::Error;
+pub use events::{ClientHandle, EventClient};
+pub use handle::{Deferred, ServerHandle};
+pub use handler::{Handler, IntoResponse};
+pub use middleware::{Flow, Logging, Middleware, SizeLimit, Timing};
+pub use phase::{Phase, PhaseConn, Phased, Phases, Transition};
+pub use pk::RecvResult;
+pub use poll::{CloseReason, Event};
+pub use reconnect::{Backoff, ConnectionState, ReconnectingClient};
+pub use router::{Discriminant, Router};
+pub use server::{Conn, ConnId, ConnInfo, ErrorPolicy, Reject, Request, Server};
+pub use stats::{ConnStats, ServerStats};
+pub use ticket::Ticket;
+#[cfg(feature = "websocket")]
+pub use ws::Codec;
+#[cfg(feature = "derive")]
+pub use srve_derive::{service, Discriminant};
+
+/// Used by the code generated by `#[service]`, not public API.
+#[doc(hidden)]
+pub mod __private {
+    pub extern crate serde;
+}
@@ -0,0 +1,196 @@
+//! Prometheus metrics, served in the text format on a port of their own.
+use crate::error::Error;
+use crate::stats::Counters;
+use log::warn;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Upper bounds of the frame size buckets, in bytes.
+const SIZE_BUCKETS: [f64; 8] = [64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+/// Upper bounds of the handler duration buckets, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+/// Names of the kinds of errors, by their index.
+const ERROR_KINDS: [&str; 5] = ["timeout", "closed", "codec", "io", "other"];
+
+/// Measurements only kept for the metrics, on top of the stats.
+pub(crate) struct Metrics {
+    frames_in: Histogram,
+    frames_out: Histogram,
+    handler: Histogram,
+    errors: [AtomicU64; 5],
+}
+
+/// Histogram updated from any thread.
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations in each bucket, the last one for those over every bound.
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    /// Scales observations before adding them to the sum, to keep it integral.
+    scale: f64,
+}
+
+impl Metrics {
+    /// Count a received frame of the given size.
+    pub fn frame_in(&self, size: usize) {
+        self.frames_in.observe(size as f64);
+    }
+
+    /// Count a sent frame of the given size.
+    pub fn frame_out(&self, size: usize) {
+        self.frames_out.observe(size as f64);
+    }
+
+    /// Count a message handled in the given time.
+    pub fn handled(&self, elapsed: Duration) {
+        self.handler.observe(elapsed.as_secs_f64());
+    }
+
+    /// Count an error by its kind.
+    pub fn error(&self, e: &Error) {
+        let kind = match e {
+            Error::Timeout => 0,
+            Error::Closed => 1,
+            Error::Codec(_) => 2,
+            Error::Io(_) => 3,
+            Error::Other(_) => 4,
+        };
+        self.errors[kind].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64], scale: f64) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            scale,
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|&bound| value <= bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add((value * self.scale) as u64, Ordering::Relaxed);
+    }
+
+    /// Write the samples of the histogram, with the given labels.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter()) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, count);
+        }
+        count += self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / self.scale;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            frames_in: Histogram::new(&SIZE_BUCKETS, 1.0),
+            frames_out: Histogram::new(&SIZE_BUCKETS, 1.0),
+            // sums durations in nanoseconds
+            handler: Histogram::new(&DURATION_BUCKETS, 1e9),
+            errors: Default::default(),
+        }
+    }
+}
+
+/// Serve the metrics of a server on the given address, on a thread of its own.
+pub(crate) fn serve(addr: &str, counters: Arc<Counters>) -> Result<(), Error> {
+    let sock = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in sock.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &counters) {
+                        warn!("metrics :: failed to respond: {}", e);
+                    }
+                }
+                Err(e) => warn!("metrics :: failed to accept: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer a single scrape.
+fn respond(mut stream: TcpStream, counters: &Counters) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // only the request line matters, which comes in the first read
+    let mut buf = [0u8; 1024];
+    let size = stream.read(&mut buf)?;
+    let request = String::from_utf8_lossy(&buf[..size]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", render(counters))
+    } else {
+        ("404 Not Found", String::from("not found\n"))
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body,
+    )?;
+    Ok(())
+}
+
+/// Write every metric in the text format.
+fn render(counters: &Counters) -> String {
+    let stats = counters.snapshot();
+    let metrics = &counters.metrics;
+    let mut out = String::new();
+    let header = |out: &mut String, name: &str, kind: &str, help: &str| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    };
+
+    header(&mut out, "srve_connections_active", "gauge", "Connections currently open.");
+    let _ = writeln!(out, "srve_connections_active {}", stats.active);
+    header(&mut out, "srve_connections_accepted_total", "counter", "Connections accepted.");
+    let _ = writeln!(out, "srve_connections_accepted_total {}", stats.accepted);
+    header(&mut out, "srve_connections_rejected_total", "counter", "Connections rejected.");
+    let _ = writeln!(out, "srve_connections_rejected_total {}", stats.rejected);
+    header(&mut out, "srve_connections_closed_total", "counter", "Connections closed, by reason.");
+    for (reason, value) in [
+        ("client", stats.closed),
+        ("unexpected", stats.closed_unexpected),
+        ("error", stats.closed_error),
+        ("panic", stats.closed_panic),
+        ("server", stats.closed_server),
+    ] {
+        let _ = writeln!(out, "srve_connections_closed_total{{reason=\"{}\"}} {}", reason, value);
+    }
+
+    header(&mut out, "srve_messages_total", "counter", "Messages, by direction.");
+    let _ = writeln!(out, "srve_messages_total{{direction=\"in\"}} {}", stats.messages_in);
+    let _ = writeln!(out, "srve_messages_total{{direction=\"out\"}} {}", stats.messages_out);
+    header(&mut out, "srve_bytes_total", "counter", "Bytes of the frames, by direction.");
+    let _ = writeln!(out, "srve_bytes_total{{direction=\"in\"}} {}", stats.bytes_in);
+    let _ = writeln!(out, "srve_bytes_total{{direction=\"out\"}} {}", stats.bytes_out);
+
+    header(&mut out, "srve_errors_total", "counter", "Errors receiving or sending, by kind.");
+    for (kind, value) in ERROR_KINDS.iter().zip(metrics.errors.iter()) {
+        let _ = writeln!(out, "srve_errors_total{{kind=\"{}\"}} {}", kind, value.load(Ordering::Relaxed));
+    }
+
+    header(&mut out, "srve_frame_size_bytes", "histogram", "Size of the frames, by direction.");
+    metrics.frames_in.render(&mut out, "srve_frame_size_bytes", "direction=\"in\"");
+    metrics.frames_out.render(&mut out, "srve_frame_size_bytes", "direction=\"out\"");
+    header(&mut out, "srve_handler_duration_seconds", "histogram", "Time taken to handle a message.");
+    metrics.handler.render(&mut out, "srve_handler_duration_seconds", "");
+    out
+}
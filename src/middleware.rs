@@ -0,0 +1,236 @@
+use crate::error::Error;
+use crate::server::{Conn, Request};
+use log::{log, warn, Level};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What to do with an inbound message, as decided by a middleware.
+pub enum Flow<Req, Resp> {
+    /// Hand the (possibly transformed) message to the next middleware, then the handler.
+    Next(Req),
+    /// Skip the rest of the chain and the handler, answering with this message instead.
+    Reply(Resp),
+    /// Skip the rest of the chain and the handler, ignoring the message.
+    Drop,
+    /// Fail the connection with this error, as when a message fails to be received.
+    Reject(Error),
+}
+
+/// Runs around the message dispatch of a `Server`, and around `Conn::send`.
+///
+/// Middlewares are added with `Server::middleware`, inbound messages go through them in
+/// the order they were added, and outbound messages in the reverse order. Every method
+/// passes messages through untouched by default.
+pub trait Middleware<S, Req, Resp> {
+    /// Run on every received message, before the handler.
+    fn inbound(&self, _conn: &Conn<S, Resp>, _request: Option<Request>, msg: Req)
+        -> Flow<Req, Resp>
+    {
+        Flow::Next(msg)
+    }
+
+    /// Run once a received message was handled, or stopped by a middleware, only for the
+    /// middlewares it went through.
+    fn after(&self, _conn: &Conn<S, Resp>) {}
+
+    /// Run on every message sent, failing the send on error, which leaves the connection
+    /// open.
+    fn outbound(&self, _conn: &Conn<S, Resp>, msg: Resp) -> Result<Resp, Error> {
+        Ok(msg)
+    }
+
+    /// Largest frames to receive and to send, in bytes, which the connections enforce
+    /// themselves: inbound ones before buffering them, outbound ones before sending them.
+    fn frame_limits(&self) -> (Option<u64>, Option<u64>) {
+        (None, None)
+    }
+}
+
+/// Middlewares of a server, in the order they were added.
+pub(crate) struct Chain<S, Req, Resp> {
+    layers: Vec<Box<dyn Middleware<S, Req, Resp> + Send + Sync>>,
+}
+
+/// The outbound half of a chain, which is all connections need.
+pub(crate) trait Outbound<S, Resp> {
+    fn outbound(&self, conn: &Conn<S, Resp>, msg: Resp) -> Result<Resp, Error>;
+
+    /// Smallest limits of the middlewares, inbound and outbound.
+    fn frame_limits(&self) -> (Option<u64>, Option<u64>);
+}
+
+impl<S, Req, Resp> Chain<S, Req, Resp> {
+    pub fn new(layers: Vec<Box<dyn Middleware<S, Req, Resp> + Send + Sync>>) -> Self {
+        Self { layers }
+    }
+
+    /// Run a received message through the chain, and the handler if it gets through.
+    pub fn dispatch<F>(&self, conn: &mut Conn<S, Resp>, request: Option<Request>, msg: Req, f: F)
+        -> Flow<(), Resp>
+    where
+        F: FnOnce(&mut Conn<S, Resp>, Req),
+    {
+        let mut flow = Flow::Next(msg);
+        let mut ran = 0;
+        for layer in self.layers.iter() {
+            let msg = match flow {
+                Flow::Next(msg) => msg,
+                _ => break,
+            };
+            flow = layer.inbound(conn, request, msg);
+            ran += 1;
+        }
+        let flow = match flow {
+            Flow::Next(msg) => {
+                f(conn, msg);
+                Flow::Next(())
+            }
+            Flow::Reply(reply) => Flow::Reply(reply),
+            Flow::Drop => Flow::Drop,
+            Flow::Reject(e) => Flow::Reject(e),
+        };
+        for layer in self.layers[..ran].iter().rev() {
+            layer.after(conn);
+        }
+        flow
+    }
+}
+
+impl<S, Req, Resp> Outbound<S, Resp> for Chain<S, Req, Resp> {
+    fn outbound(&self, conn: &Conn<S, Resp>, msg: Resp) -> Result<Resp, Error> {
+        self.layers.iter().rev().try_fold(msg, |msg, layer| layer.outbound(conn, msg))
+    }
+
+    fn frame_limits(&self) -> (Option<u64>, Option<u64>) {
+        let min = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.layers.iter().fold((None, None), |(inbound, outbound), layer| {
+            let (i, o) = layer.frame_limits();
+            (min(inbound, i), min(outbound, o))
+        })
+    }
+}
+
+/// Logs every message in and out of a connection.
+pub struct Logging {
+    level: Level,
+}
+
+impl Logging {
+    /// Log messages at the given level.
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+}
+
+impl Default for Logging {
+    /// Logs at the debug level.
+    fn default() -> Self {
+        Self::new(Level::Debug)
+    }
+}
+
+impl<S, Req, Resp> Middleware<S, Req, Resp> for Logging
+where
+    Req: Debug,
+    Resp: Debug,
+{
+    fn inbound(&self, conn: &Conn<S, Resp>, request: Option<Request>, msg: Req)
+        -> Flow<Req, Resp>
+    {
+        match request {
+            Some(request) => {
+                log!(self.level, "{} :: <- request {}: {:?}", conn.addr, request.id(), msg);
+            }
+            None => log!(self.level, "{} :: <- {:?}", conn.addr, msg),
+        }
+        Flow::Next(msg)
+    }
+
+    fn outbound(&self, conn: &Conn<S, Resp>, msg: Resp) -> Result<Resp, Error> {
+        log!(self.level, "{} :: -> {:?}", conn.addr, msg);
+        Ok(msg)
+    }
+}
+
+/// Measures how long each message takes to be handled.
+///
+/// Every duration is logged at the debug level, and the ones over the slow threshold, if
+/// any, as warnings.
+#[derive(Default)]
+pub struct Timing {
+    slow: Option<Duration>,
+    started: Mutex<Option<Instant>>,
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Warn about messages taking longer than this to be handled.
+    pub fn slow(mut self, threshold: Duration) -> Self {
+        self.slow = Some(threshold);
+        self
+    }
+}
+
+impl<S, Req, Resp> Middleware<S, Req, Resp> for Timing {
+    fn inbound(&self, _conn: &Conn<S, Resp>, _request: Option<Request>, msg: Req)
+        -> Flow<Req, Resp>
+    {
+        *self.started.lock().unwrap() = Some(Instant::now());
+        Flow::Next(msg)
+    }
+
+    fn after(&self, conn: &Conn<S, Resp>) {
+        let started = self.started.lock().unwrap().take();
+        if let Some(started) = started {
+            let elapsed = started.elapsed();
+            match self.slow {
+                Some(slow) if elapsed > slow => {
+                    warn!("{} :: slow message, handled in {:?}", conn.addr, elapsed);
+                }
+                _ => log!(Level::Debug, "{} :: handled in {:?}", conn.addr, elapsed),
+            }
+        }
+    }
+}
+
+/// Limits the size of frames, in bytes, as counted in the stats.
+///
+/// Inbound frames over the limit fail the connection as soon as their length is known,
+/// without buffering them, and sending outbound ones fails with the size error, leaving
+/// the connection open.
+pub struct SizeLimit {
+    inbound: u64,
+    outbound: u64,
+}
+
+impl SizeLimit {
+    /// Limit frames in both directions to the given size.
+    pub fn new(max: u64) -> Self {
+        Self { inbound: max, outbound: max }
+    }
+
+    /// Limit inbound frames to the given size.
+    pub fn inbound(mut self, max: u64) -> Self {
+        self.inbound = max;
+        self
+    }
+
+    /// Limit outbound frames to the given size.
+    pub fn outbound(mut self, max: u64) -> Self {
+        self.outbound = max;
+        self
+    }
+}
+
+impl<S, Req, Resp> Middleware<S, Req, Resp> for SizeLimit {
+    fn frame_limits(&self) -> (Option<u64>, Option<u64>) {
+        (Some(self.inbound), Some(self.outbound))
+    }
+}
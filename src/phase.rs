@@ -0,0 +1,211 @@
+use crate::error::Error;
+use crate::handler::Handler;
+use crate::server::{Conn, Request};
+use log::{info, warn};
+use serde::Serialize;
+use std::any;
+use std::convert::TryFrom;
+
+/// Connection of a server whose connections go through phases.
+pub type PhaseConn<Req, Resp = Req> = Conn<Phased<Req, Resp>, Resp>;
+
+/// A phase of a connection, e.g. handshake, authenticated, active.
+///
+/// Each phase only accepts its own messages, converted from the messages of the server, and
+/// holds its own state, handed on to the next phase when building it. Messages that are not
+/// valid in the current phase are given to `on_invalid`, which closes the connection by
+/// default.
+pub trait Phase<Req, Resp = Req>: Send {
+    /// Messages valid in this phase.
+    type Msg: TryFrom<Req>;
+
+    /// Run on every message valid in this phase, also given the request if it is one.
+    fn on_message(&mut self, conn: &mut PhaseConn<Req, Resp>, request: Option<Request>, msg: Self::Msg)
+        -> Transition<Req, Resp>;
+
+    /// Run when the connection enters this phase, before any of its messages.
+    fn on_enter(&mut self, _conn: &mut PhaseConn<Req, Resp>) {}
+
+    /// Run on every message that is not valid in this phase.
+    fn on_invalid(&mut self, _conn: &mut PhaseConn<Req, Resp>, _request: Option<Request>)
+        -> Transition<Req, Resp>
+    {
+        Transition::close()
+    }
+
+    /// Run when the connection ends in this phase, unless closed with `Conn::close`.
+    fn on_closed(&mut self, _conn: &mut PhaseConn<Req, Resp>) {}
+
+    /// Name of the phase, for the logs.
+    fn name(&self) -> &'static str {
+        any::type_name::<Self>()
+    }
+}
+
+/// What a connection does after a message of its current phase.
+pub struct Transition<Req, Resp = Req>(Next<Req, Resp>);
+
+enum Next<Req, Resp> {
+    Stay,
+    To(Box<dyn AnyPhase<Req, Resp>>),
+    Close,
+}
+
+/// State of a connection going through phases, see `Phases`.
+///
+/// Start connections in their first phase with `Server::bind_with`.
+pub struct Phased<Req, Resp = Req> {
+    /// Taken out while running.
+    phase: Option<Box<dyn AnyPhase<Req, Resp>>>,
+}
+
+/// Handler dispatching the messages of every connection to its current phase.
+///
+/// ```ignore
+/// Server::<Phased<Msg>, Msg>::bind_with(addr, |_| Ok(Phased::new(Handshake)))?
+///     .serve(Phases);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Phases;
+
+/// A phase, whatever its messages.
+trait AnyPhase<Req, Resp>: Send {
+    fn dispatch(&mut self, conn: &mut PhaseConn<Req, Resp>, request: Option<Request>, msg: Req)
+        -> Transition<Req, Resp>;
+    fn enter(&mut self, conn: &mut PhaseConn<Req, Resp>);
+    fn closed(&mut self, conn: &mut PhaseConn<Req, Resp>);
+    fn name(&self) -> &'static str;
+}
+
+impl<P, Req, Resp> AnyPhase<Req, Resp> for P
+where
+    P: Phase<Req, Resp>,
+{
+    fn dispatch(&mut self, conn: &mut PhaseConn<Req, Resp>, request: Option<Request>, msg: Req)
+        -> Transition<Req, Resp>
+    {
+        match P::Msg::try_from(msg) {
+            Ok(msg) => self.on_message(conn, request, msg),
+            Err(_) => {
+                warn!("{} :: message not valid in phase {}", conn.addr, self.name());
+                self.on_invalid(conn, request)
+            }
+        }
+    }
+
+    fn enter(&mut self, conn: &mut PhaseConn<Req, Resp>) {
+        self.on_enter(conn)
+    }
+
+    fn closed(&mut self, conn: &mut PhaseConn<Req, Resp>) {
+        self.on_closed(conn)
+    }
+
+    fn name(&self) -> &'static str {
+        Phase::name(self)
+    }
+}
+
+impl<Req, Resp> Transition<Req, Resp> {
+    /// Remain in the current phase.
+    pub fn stay() -> Self {
+        Transition(Next::Stay)
+    }
+
+    /// Move on to the given phase.
+    pub fn to<P>(phase: P) -> Self
+    where
+        P: Phase<Req, Resp> + 'static,
+    {
+        Transition(Next::To(Box::new(phase)))
+    }
+
+    /// Close the connection.
+    pub fn close() -> Self {
+        Transition(Next::Close)
+    }
+}
+
+impl<Req, Resp> Phased<Req, Resp> {
+    /// Start in the given phase.
+    pub fn new<P>(phase: P) -> Self
+    where
+        P: Phase<Req, Resp> + 'static,
+    {
+        Self { phase: Some(Box::new(phase)) }
+    }
+
+    /// Name of the current phase, none from within the phase itself.
+    pub fn phase(&self) -> Option<&'static str> {
+        self.phase.as_ref().map(|phase| phase.name())
+    }
+}
+
+impl Phases {
+    fn dispatch<Req, Resp>(conn: &mut PhaseConn<Req, Resp>, request: Option<Request>, msg: Req)
+    where
+        Resp: Serialize,
+    {
+        // only missing if a phase panicked, which closed the connection
+        let mut phase = match conn.phase.take() {
+            Some(phase) => phase,
+            None => return,
+        };
+        match phase.dispatch(conn, request, msg).0 {
+            Next::Stay => conn.phase = Some(phase),
+            Next::To(mut next) => {
+                info!("{} :: phase {} -> {}", conn.addr, phase.name(), next.name());
+                next.enter(conn);
+                conn.phase = Some(next);
+            }
+            Next::Close => {
+                info!("{} :: closed in phase {}", conn.addr, phase.name());
+                let _ = conn.close();
+                phase.closed(conn);
+                conn.phase = Some(phase);
+            }
+        }
+    }
+
+    fn closed<Req, Resp>(conn: &mut PhaseConn<Req, Resp>) {
+        if let Some(mut phase) = conn.phase.take() {
+            phase.closed(conn);
+            conn.phase = Some(phase);
+        }
+    }
+}
+
+impl<Req, Resp> Handler<Phased<Req, Resp>, Req, Resp> for Phases
+where
+    Resp: Serialize,
+{
+    fn on_connection(&mut self, conn: &mut PhaseConn<Req, Resp>) {
+        if let Some(mut phase) = conn.phase.take() {
+            phase.enter(conn);
+            conn.phase = Some(phase);
+        }
+    }
+
+    fn on_message(&mut self, conn: &mut PhaseConn<Req, Resp>, msg: Req) {
+        Self::dispatch(conn, None, msg);
+    }
+
+    fn on_request(&mut self, conn: &mut PhaseConn<Req, Resp>, request: Request, msg: Req) {
+        Self::dispatch(conn, Some(request), msg);
+    }
+
+    fn on_closed(&mut self, conn: &mut PhaseConn<Req, Resp>) {
+        Self::closed(conn);
+    }
+
+    fn on_closed_unexpected(&mut self, conn: &mut PhaseConn<Req, Resp>) {
+        Self::closed(conn);
+    }
+
+    fn on_error(&mut self, conn: &mut PhaseConn<Req, Resp>, _e: Error) {
+        // skipped bad messages leave the connection in its phase
+        if conn.is_closing() {
+            Self::closed(conn);
+        }
+    }
+}
@@ -1,15 +1,18 @@
+use crate::error::Error;
 use bincode::{serialize, deserialize};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use std::error::Error;
-use std::io::{Write, Read, ErrorKind};
-use std::net::TcpStream;
+use std::io::{self, Write, Read, ErrorKind};
+use std::mem;
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
 
 /// Possible results when attempting to receive a message.
+#[derive(Debug)]
 pub enum RecvResult<M> {
     /// We succesfully got a message.
     Some(M),
-    /// The stream is currently empty.
+    /// There is no complete message yet.
     None,
     /// The stream was closed correctly.
     Closed,
@@ -17,96 +20,332 @@ pub enum RecvResult<M> {
     ClosedWrongly,
 }
 
-/// Send a message via a tcp stream (blocking).
-pub fn send<M>(msg: M, stream: &mut TcpStream) -> Result<(), Box<dyn Error>>
-where
-    M: Serialize
-{
+impl<M> RecvResult<M> {
+    /// Turn the result of a blocking receive into the message, or the matching error.
+    pub(crate) fn into_result(self) -> Result<M, Error> {
+        match self {
+            RecvResult::Some(msg) => Ok(msg),
+            RecvResult::None => Err(Error::Timeout),
+            RecvResult::Closed => Err(Error::Closed),
+            RecvResult::ClosedWrongly => Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    /// Apply a function to the message, if there is one.
+    pub(crate) fn map<N, F>(self, f: F) -> RecvResult<N>
+    where
+        F: FnOnce(M) -> N
+    {
+        match self {
+            RecvResult::Some(msg) => RecvResult::Some(f(msg)),
+            RecvResult::None => RecvResult::None,
+            RecvResult::Closed => RecvResult::Closed,
+            RecvResult::ClosedWrongly => RecvResult::ClosedWrongly,
+        }
+    }
+}
+
+/// Sent in every frame, before the message itself.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Header {
+    /// Set when the message is a request, expecting a reply with the same id.
+    pub id: Option<u64>,
+    /// Set when the message is the reply to the request with this id.
+    pub reply_to: Option<u64>,
+    /// Set to correlate the message with the logs of its sender, replies carry it back.
+    pub trace: Option<u64>,
+}
+
+/// Write a frame to a tcp stream (blocking), returns its size.
+pub fn write_frame(frame: &[u8], stream: &mut TcpStream) -> Result<usize, Error> {
     // make sure we can block (necessary?).
     stream.set_nonblocking(false)?;
 
-    // attempt serialization of the message
-    let data = serialize(&msg)?;
-    let len = serialize(&(data.len() as u64))?;
-
     // attempt to write to stream
-    stream.write_all(len.as_slice())?;
-    stream.write_all(data.as_slice())?;
+    if let Err(e) = stream.write_all(frame) {
+        return Err(match e.kind() {
+            // part of the frame may be written already, the next one would be misread
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                let _ = stream.shutdown(Shutdown::Both);
+                Error::Io(io::Error::new(ErrorKind::TimedOut, "timed out writing a frame, shut down the stream"))
+            }
+            _ => e.into(),
+        });
+    }
 
-    Ok(())
+    Ok(frame.len())
 }
 
-/// Attempts to receive a message from the tcp stream (blocking).
-pub fn recv<M>(stream: &mut TcpStream) -> Result<M, Box<dyn Error>>
+/// Serialize a message into a length prefixed frame.
+pub fn encode_frame<M>(header: Header, msg: M) -> Result<Vec<u8>, Error>
 where
-    M: DeserializeOwned
+    M: Serialize
 {
-    // we want to block
-    stream.set_nonblocking(false)?;
+    let data = serialize(&(header, msg))?;
+    let mut frame = serialize(&(data.len() as u64))?;
+    frame.extend_from_slice(data.as_slice());
+    Ok(frame)
+}
 
-    // get length first
-    let mut buf = [0u8; 8];
-    stream.read_exact(&mut buf[..])?;
-    let len = deserialize::<u64>(&buf[..])? as usize;
+/// Fail if a frame of the given size, as counted in the stats, is over the limit.
+pub fn check_size(size: u64, max: Option<u64>) -> Result<(), Error> {
+    match max {
+        Some(max) if size > max => {
+            Err(Error::Other(format!("frame of {} bytes is over the limit of {}", size, max)))
+        }
+        _ => Ok(()),
+    }
+}
 
-    // then get the message
-    let mut buf = Vec::new();
-    buf.resize(len, 0u8);
-    stream.read_exact(buf.as_mut_slice())?;
-    Ok(deserialize(buf.as_slice())?)
+/// Deserialize a message from a complete length prefixed frame.
+#[cfg(feature = "websocket")]
+pub fn decode_frame<M>(frame: &[u8]) -> Result<(Header, M), Error>
+where
+    M: DeserializeOwned
+{
+    if frame.len() < 8 {
+        return Err(Error::Codec("malformed frame: missing length".into()));
+    }
+    let len = deserialize::<u64>(&frame[..8])? as usize;
+    if frame.len() - 8 != len {
+        return Err(Error::Codec("malformed frame: length mismatch".into()));
+    }
+    decode_body(&frame[8..])
 }
 
-/// Return immediately if there are no incoming messages, otherwise will block until we can
-/// receive a complete message.
-pub fn try_recv<M>(stream: &mut TcpStream) -> Result<RecvResult<M>, Box<dyn Error>>
+/// Deserialize the header and message making up the body of a frame.
+fn decode_body<M>(mut body: &[u8]) -> Result<(Header, M), Error>
 where
     M: DeserializeOwned
 {
-    // we do not want to block
-    stream.set_nonblocking(true)?;
-
-    // peek a byte
-    // TODO: possible improvement here ?
-    let mut buf = [0u8; 1];
-    match stream.peek(&mut buf[..]) {
-        Ok(size) => {
-            if size == 0 { // stream was closed correctly
-                return Ok(RecvResult::Closed);
+    let header: Header = bincode::deserialize_from(&mut body)?;
+    Ok((header, deserialize(body)?))
+}
+
+/// Buffers incoming bytes from a tcp stream until a complete frame is available.
+///
+/// This way a partially received message never blocks the caller, it is simply kept
+/// around until the rest of it arrives.
+#[derive(Default)]
+pub struct FrameReader {
+    /// Bytes received but not yet consumed as a frame.
+    buf: Vec<u8>,
+    /// Bytes consumed as frames, since last taken.
+    consumed: usize,
+    /// Largest frame to accept, length prefix included.
+    max: Option<u64>,
+}
+
+impl FrameReader {
+    /// Attempts to receive a message from the tcp stream (blocking).
+    ///
+    /// With a timeout, fails with `Error::Timeout` if no message arrived in time.
+    pub fn recv<M>(&mut self, stream: &mut TcpStream, timeout: Option<Duration>)
+        -> Result<(Header, M), Error>
+    where
+        M: DeserializeOwned
+    {
+        let res = match timeout {
+            Some(timeout) => self.recv_timeout(stream, timeout)?,
+            None => {
+                // we want to block
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(None)?;
+                loop {
+                    match self.read(stream)? {
+                        RecvResult::None => continue,
+                        res => break res,
+                    }
+                }
             }
-        }
-        Err(ref e) if e.kind() == ErrorKind::WouldBlock => { // stream is empty
-            return Ok(RecvResult::None);
-        }
-        Err(e) => return Err(Box::new(e)),
+        };
+        res.into_result()
     }
 
-    // now that we can expect a message, block
-    stream.set_nonblocking(false)?;
+    /// Return immediately if there is no complete message in the stream.
+    pub fn try_recv<M>(&mut self, stream: &mut TcpStream)
+        -> Result<RecvResult<(Header, M)>, Error>
+    where
+        M: DeserializeOwned
+    {
+        // we do not want to block
+        stream.set_nonblocking(true)?;
+        self.read(stream)
+    }
 
-    // get length first
-    let mut buf = [0u8; 8];
-    match stream.read_exact(&mut buf[..]) {
-        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => { /* closed wrongly */
-            return Ok(RecvResult::ClosedWrongly);
+    /// Block until a complete message arrives, or the timeout elapses.
+    pub fn recv_timeout<M>(&mut self, stream: &mut TcpStream, timeout: Duration)
+        -> Result<RecvResult<(Header, M)>, Error>
+    where
+        M: DeserializeOwned
+    {
+        stream.set_nonblocking(false)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                // one last look at what is already there
+                return self.try_recv(stream);
+            }
+            stream.set_read_timeout(Some(remaining))?;
+            match self.read(stream)? {
+                RecvResult::None => continue,
+                res => return Ok(res),
+            }
         }
-        Err(e) => { /* another error */
-            return Err(Box::new(e));
+    }
+
+    /// Fail on frames larger than the given size, as soon as their length is known.
+    pub fn set_max(&mut self, max: Option<u64>) {
+        self.max = max;
+    }
+
+    /// Take the amount of bytes consumed as frames since the last call.
+    pub fn take_consumed(&mut self) -> usize {
+        mem::take(&mut self.consumed)
+    }
+
+    /// Read from the stream until a complete frame is buffered, or the stream would block.
+    fn read<M>(&mut self, stream: &mut TcpStream) -> Result<RecvResult<(Header, M)>, Error>
+    where
+        M: DeserializeOwned
+    {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(frame) = self.next_frame()? {
+                return Ok(RecvResult::Some(decode_body(frame.as_slice())?));
+            }
+
+            match stream.read(&mut chunk[..]) {
+                Ok(0) if self.buf.is_empty() => { /* closed correctly */
+                    return Ok(RecvResult::Closed);
+                }
+                Ok(0) => { /* closed in the middle of a message */
+                    return Ok(RecvResult::ClosedWrongly);
+                }
+                Ok(size) => self.buf.extend_from_slice(&chunk[..size]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => { /* nothing more for now */
+                    return Ok(RecvResult::None);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => { /* another error */
+                    return Err(e.into());
+                }
+            }
         }
-        Ok(()) => { /* success */ }
-    };
-    let len = deserialize::<u64>(&buf[..])? as usize;
-
-    // then get the message
-    let mut buf = Vec::new();
-    buf.resize(len, 0u8);
-    match stream.read_exact(buf.as_mut_slice()) {
-        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => { /* closed wrongly */
-            return Ok(RecvResult::ClosedWrongly);
+    }
+
+    /// Take the payload of the next complete frame out of the buffer, if there is one.
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        // get length first
+        if self.buf.len() < 8 {
+            return Ok(None);
         }
-        Err(e) => { /* another error */
-            return Err(Box::new(e));
+        let len = deserialize::<u64>(&self.buf[..8])?;
+        // the stream cannot be read any further, since the frame is never buffered
+        check_size(len.saturating_add(8), self.max)?;
+        let len = len as usize;
+
+        // then get the message
+        if self.buf.len() - 8 < len {
+            return Ok(None);
         }
-        Ok(()) => { /* success */ }
+        let frame = self.buf[8..8 + len].to_vec();
+        self.buf.drain(..8 + len);
+        self.consumed += 8 + len;
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Connected pair of streams, the one written to and the one read from.
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        (writer, reader)
+    }
+
+    fn frame(msg: u32) -> Vec<u8> {
+        encode_frame(Header { id: Some(msg as u64), ..Header::default() }, msg).unwrap()
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let (mut writer, mut reader) = pair();
+        let mut frames = FrameReader::default();
+        let frame = frame(7);
+        let (first, rest) = frame.split_at(frame.len() / 2);
+
+        writer.write_all(first).unwrap();
+        let res = frames.recv_timeout::<u32>(&mut reader, Duration::from_millis(100)).unwrap();
+        assert!(matches!(res, RecvResult::None));
+
+        writer.write_all(rest).unwrap();
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT).unwrap();
+        assert!(matches!(res, RecvResult::Some((Header { id: Some(7), .. }, 7))));
+        assert_eq!(frames.take_consumed(), frame.len());
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let (mut writer, mut reader) = pair();
+        let mut frames = FrameReader::default();
+        let mut both = frame(1);
+        both.extend(frame(2));
+        writer.write_all(&both).unwrap();
+
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT).unwrap();
+        assert!(matches!(res, RecvResult::Some((_, 1))));
+        // already buffered, no need to wait for it
+        let res = frames.try_recv::<u32>(&mut reader).unwrap();
+        assert!(matches!(res, RecvResult::Some((_, 2))));
+    }
+
+    #[test]
+    fn eof_mid_frame_is_closed_wrongly() {
+        let (mut writer, mut reader) = pair();
+        let mut frames = FrameReader::default();
+        let frame = frame(3);
+        writer.write_all(&frame[..frame.len() - 1]).unwrap();
+        drop(writer);
+
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT).unwrap();
+        assert!(matches!(res, RecvResult::ClosedWrongly));
+    }
+
+    #[test]
+    fn bad_body_is_skipped() {
+        let (mut writer, mut reader) = pair();
+        let mut frames = FrameReader::default();
+        // a valid length, with a header that cannot be decoded
+        let mut bytes = serialize(&3u64).unwrap();
+        bytes.extend_from_slice(&[0xff, 0xff, 0xff]);
+        bytes.extend(frame(4));
+        writer.write_all(&bytes).unwrap();
+
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT);
+        assert!(matches!(res, Err(ref e) if e.is_decode()));
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT).unwrap();
+        assert!(matches!(res, RecvResult::Some((_, 4))));
+    }
+
+    #[test]
+    fn frame_over_limit_fails_before_buffering() {
+        let (mut writer, mut reader) = pair();
+        let mut frames = FrameReader::default();
+        frames.set_max(Some(64));
+        // only the length is sent, the frame itself never needs to arrive
+        writer.write_all(&serialize(&(1u64 << 40)).unwrap()).unwrap();
+
+        let res = frames.recv_timeout::<u32>(&mut reader, TIMEOUT);
+        assert!(matches!(res, Err(Error::Other(_))));
     }
-    Ok(RecvResult::Some(deserialize(buf.as_slice())?))
 }
@@ -0,0 +1,89 @@
+use crate::error::Error;
+use crate::handler::Handler;
+use crate::server::{Conn, ConnId, Request};
+
+/// Something that happened on a connection, returned by `Server::poll`.
+#[derive(Debug)]
+pub enum Event<Req> {
+    /// A client connected.
+    Connected(ConnId),
+    /// A connection sent a message.
+    Message(ConnId, Req),
+    /// A connection sent a request, to be answered with `Server::reply`.
+    Request(ConnId, Request, Req),
+    /// A timer set with `Conn::schedule_after` is due, with its event.
+    Timer(ConnId, u64),
+    /// A message failed to be decoded and was skipped, as told by the error policy, or a
+    /// reply was rejected before being written, the connection remains open.
+    Error(ConnId, Error),
+    /// A connection was closed, and dropped by the server.
+    Closed(ConnId, CloseReason),
+}
+
+/// Why a connection was closed.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The client terminated the connection.
+    Closed,
+    /// The client closed the connection in the middle of a message.
+    ClosedUnexpected,
+    /// Getting the next message failed, or a middleware rejected it.
+    Error(Error),
+    /// Handling the connection panicked, with this message.
+    Panicked(String),
+    /// The server closed the connection, e.g. with `Server::close` or `Conn::close`.
+    Server,
+}
+
+/// Handler turning every event into an `Event`.
+pub(crate) struct Collect<'a, Req> {
+    events: &'a mut Vec<Event<Req>>,
+}
+
+impl<'a, Req> Collect<'a, Req> {
+    pub fn new(events: &'a mut Vec<Event<Req>>) -> Self {
+        Self { events }
+    }
+}
+
+impl<'a, S, Req, Resp> Handler<S, Req, Resp> for Collect<'a, Req> {
+    fn on_connection(&mut self, conn: &mut Conn<S, Resp>) {
+        self.events.push(Event::Connected(conn.id()));
+    }
+
+    fn on_message(&mut self, conn: &mut Conn<S, Resp>, msg: Req) {
+        self.events.push(Event::Message(conn.id(), msg));
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S, Resp>, request: Request, msg: Req) {
+        self.events.push(Event::Request(conn.id(), request, msg));
+    }
+
+    fn on_closed(&mut self, conn: &mut Conn<S, Resp>) {
+        self.events.push(Event::Closed(conn.id(), CloseReason::Closed));
+    }
+
+    fn on_closed_unexpected(&mut self, conn: &mut Conn<S, Resp>) {
+        self.events.push(Event::Closed(conn.id(), CloseReason::ClosedUnexpected));
+    }
+
+    fn on_closed_by_server(&mut self, conn: &mut Conn<S, Resp>) {
+        self.events.push(Event::Closed(conn.id(), CloseReason::Server));
+    }
+
+    fn on_error(&mut self, conn: &mut Conn<S, Resp>, e: Error) {
+        if conn.is_closing() {
+            self.events.push(Event::Closed(conn.id(), CloseReason::Error(e)));
+        } else {
+            self.events.push(Event::Error(conn.id(), e));
+        }
+    }
+
+    fn on_timer(&mut self, conn: &mut Conn<S, Resp>, event: u64) {
+        self.events.push(Event::Timer(conn.id(), event));
+    }
+
+    fn on_panic(&mut self, conn: &mut Conn<S, Resp>, msg: &str) {
+        self.events.push(Event::Closed(conn.id(), CloseReason::Panicked(msg.to_string())));
+    }
+}
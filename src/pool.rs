@@ -0,0 +1,61 @@
+use log::warn;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+/// Work for the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running slow work away from the server loop.
+///
+/// The threads are only started with the first job, and stop once the pool is dropped.
+pub(crate) struct Pool {
+    /// Amount of threads to start.
+    size: AtomicUsize,
+    /// Hands jobs to the threads, once started.
+    jobs: OnceLock<Sender<Job>>,
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: AtomicUsize::new(size),
+            jobs: OnceLock::new(),
+        }
+    }
+
+    /// Set the amount of threads, which has no effect once they are started.
+    pub fn resize(&self, size: usize) {
+        self.size.store(size.max(1), Ordering::Relaxed);
+    }
+
+    /// Run a job on one of the threads.
+    pub fn spawn(&self, job: Job) {
+        let jobs = self.jobs.get_or_init(|| self.start());
+        // the threads outlive the sender, since they never panic
+        let _ = jobs.send(job);
+    }
+
+    fn start(&self) -> Sender<Job> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..self.size.load(Ordering::Relaxed) {
+            let rx = rx.clone();
+            thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => {
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            warn!("panicked running a job on the pool");
+                        }
+                    }
+                    // the pool is gone
+                    Err(_) => break,
+                }
+            });
+        }
+        tx
+    }
+}
@@ -0,0 +1,356 @@
+use crate::client::{Client, ClientBuilder};
+use crate::error::Error;
+use crate::pk::RecvResult;
+#[cfg(feature = "websocket")]
+use crate::ws::Codec;
+use log::{info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Hook run on every newly established connection.
+type ReconnectCallback<Req, Resp> =
+    Box<dyn FnMut(&mut Client<Req, Resp>) -> Result<(), Error> + Send>;
+/// Hook run on every connection state change.
+type StateCallback = Box<dyn FnMut(&ConnectionState) + Send>;
+
+/// A client that transparently reconnects to the server when the connection is lost.
+///
+/// The connection is established lazily, on the first operation that needs it.
+pub struct ReconnectingClient<Req, Resp = Req> {
+    /// Where to connect to.
+    target: Target,
+    /// How to connect.
+    builder: ClientBuilder,
+    /// How long to wait between failed attempts.
+    backoff: Backoff,
+    /// Current connection, if any.
+    client: Option<Client<Req, Resp>>,
+    /// Current state, as last reported.
+    state: ConnectionState,
+    /// Failed attempts since the last successful connection.
+    attempts: u32,
+    /// When we are allowed to attempt connecting again.
+    next_attempt: Instant,
+
+    cb_reconnect: Option<ReconnectCallback<Req, Resp>>,
+    cb_state: Option<StateCallback>,
+}
+
+/// State of a reconnecting client's connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// There is a working connection to the server.
+    Connected,
+    /// The connection was lost (or never established), a new one will be attempted.
+    Disconnected,
+    /// An attempt failed, the next one is scheduled after the given delay.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The maximum amount of attempts was reached, operations will fail from now on.
+    GaveUp,
+}
+
+/// Exponential backoff between reconnection attempts.
+///
+/// After a connection is lost a new one is attempted right away, then each failed attempt
+/// multiplies the delay by `factor`, up to `max`, randomly adjusted by `jitter`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+/// Where a reconnecting client connects to.
+enum Target {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(feature = "websocket")]
+    Ws(String, Codec),
+}
+
+impl Backoff {
+    /// Backoff starting at `initial` and never waiting longer than `max`.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            factor: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+
+    /// Multiply the delay by this after every failed attempt (default `2.0`).
+    pub fn factor(mut self, factor: f64) -> Self {
+        self.factor = factor.max(1.0);
+        self
+    }
+
+    /// Randomly adjust each delay by up to this fraction of it (default `0.2`), so many
+    /// clients do not all reconnect at once.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after this many failed attempts in a row (default: never).
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Delay after the given amount of failed attempts.
+    fn delay(&self, attempts: u32) -> Duration {
+        let exp = self.factor.powi(attempts.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let base = self.initial.as_secs_f64() * exp;
+        let base = base.min(self.max.as_secs_f64());
+        // random value in [-1, 1)
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(attempts);
+        let rand = (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0;
+        Duration::from_secs_f64((base * (1.0 + self.jitter * rand)).max(0.0))
+    }
+}
+
+impl Default for Backoff {
+    /// Starts at 100ms and waits at most 30s.
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100), Duration::from_secs(30))
+    }
+}
+
+impl<Req, Resp> ReconnectingClient<Req, Resp>
+where
+    Req: Serialize + Debug,
+    Resp: DeserializeOwned + Debug,
+{
+    /// Create a client for the server at the given address, resolved right away.
+    pub fn new<A>(addr: A) -> Result<Self, Error>
+    where
+        A: ToSocketAddrs,
+    {
+        let addrs = addr.to_socket_addrs()?.collect();
+        Ok(Self::with_target(Target::Tcp(addrs)))
+    }
+
+    /// Create a client for the WebSocket server at the given url.
+    #[cfg(feature = "websocket")]
+    pub fn new_ws(url: &str, codec: Codec) -> Self {
+        Self::with_target(Target::Ws(url.to_string(), codec))
+    }
+
+    fn with_target(target: Target) -> Self {
+        Self {
+            target,
+            builder: ClientBuilder::new(),
+            backoff: Backoff::default(),
+            client: None,
+            state: ConnectionState::Disconnected,
+            attempts: 0,
+            next_attempt: Instant::now(),
+            cb_reconnect: None,
+            cb_state: None,
+        }
+    }
+
+    /// Use the given builder (timeouts, socket options) for every connection.
+    pub fn builder(mut self, builder: ClientBuilder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    /// Use the given backoff between failed attempts.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Setup a hook for new connections.
+    ///
+    /// This hook is run every time a connection is established, including the first one,
+    /// _before_ any other message is sent, e.g. to log in or subscribe again. If it fails,
+    /// the attempt counts as a failed one.
+    pub fn on_reconnect<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&mut Client<Req, Resp>) -> Result<(), Error> + Send + 'static
+    {
+        self.cb_reconnect = Some(Box::new(cb));
+        self
+    }
+
+    /// Setup a callback for connection state changes.
+    pub fn on_state_change<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(&ConnectionState) + Send + 'static
+    {
+        self.cb_state = Some(Box::new(cb));
+        self
+    }
+
+    /// Current state of the connection.
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    /// Send a message to the server, reconnecting first if needed.
+    ///
+    /// If sending fails because the connection was lost, the message is sent once more
+    /// over a new connection.
+    pub fn send(&mut self, msg: Req) -> Result<(), Error> {
+        let res = self.connected(None)?.send_ref(&msg);
+        match res {
+            Err(e) if is_disconnect(&e) => {
+                self.disconnect(&e);
+                self.connected(None)?.send_ref(&msg)
+            }
+            res => res,
+        }
+    }
+
+    /// Receive a message from the server (blocks), reconnecting as needed.
+    pub fn recv(&mut self) -> Result<Resp, Error> {
+        loop {
+            let res = self.connected(None)?.recv();
+            match res {
+                Err(e) if is_disconnect(&e) => self.disconnect(&e),
+                res => return res,
+            }
+        }
+    }
+
+    /// Receive a message from the server if one is available, returns immediately.
+    ///
+    /// While disconnected, this makes a single connection attempt if one is due, and
+    /// returns `RecvResult::None` otherwise.
+    pub fn try_recv(&mut self) -> Result<RecvResult<Resp>, Error> {
+        self.recv_until(Instant::now())
+    }
+
+    /// Receive a message from the server, blocking at most for the given duration,
+    /// reconnecting as needed in the meantime.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<RecvResult<Resp>, Error> {
+        self.recv_until(Instant::now() + timeout)
+    }
+
+    /// Closes the connection to the server, if any.
+    pub fn close(self) -> Result<(), Error> {
+        match self.client {
+            Some(client) => client.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// Receive until a message arrives or the deadline passes.
+    fn recv_until(&mut self, deadline: Instant) -> Result<RecvResult<Resp>, Error> {
+        loop {
+            let client = match self.connected(Some(deadline)) {
+                Ok(client) => client,
+                Err(Error::Timeout) => return Ok(RecvResult::None),
+                Err(e) => return Err(e),
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match client.recv_timeout(remaining) {
+                Ok(RecvResult::Closed) => self.disconnect(&Error::Closed),
+                Ok(RecvResult::ClosedWrongly) => {
+                    self.disconnect(&Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                Err(e) if is_disconnect(&e) => self.disconnect(&e),
+                res => return res,
+            }
+        }
+    }
+
+    /// Get the current connection, attempting new ones (blocking) until one succeeds or the
+    /// deadline passes, which fails with `Error::Timeout`.
+    fn connected(&mut self, deadline: Option<Instant>) -> Result<&mut Client<Req, Resp>, Error> {
+        while self.client.is_none() {
+            if self.state == ConnectionState::GaveUp {
+                return Err(Error::Closed);
+            }
+            let now = Instant::now();
+            if self.next_attempt > now {
+                match deadline {
+                    Some(deadline) if deadline < self.next_attempt => {
+                        thread::sleep(deadline.saturating_duration_since(now));
+                        return Err(Error::Timeout);
+                    }
+                    _ => thread::sleep(self.next_attempt - now),
+                }
+            }
+            self.attempt();
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    /// Make a single connection attempt, scheduling the next one if it fails.
+    fn attempt(&mut self) {
+        let res = self.open().and_then(|mut client| {
+            if let Some(cb) = self.cb_reconnect.as_mut() {
+                cb(&mut client)?;
+            }
+            Ok(client)
+        });
+        match res {
+            Ok(client) => {
+                info!("reconnecting client :: connected");
+                self.client = Some(client);
+                self.attempts = 0;
+                self.set_state(ConnectionState::Connected);
+            }
+            Err(e) => {
+                self.attempts += 1;
+                warn!("reconnecting client :: attempt {} failed: {}", self.attempts, e);
+                match self.backoff.max_attempts {
+                    Some(max) if self.attempts >= max => self.set_state(ConnectionState::GaveUp),
+                    _ => {
+                        let delay = self.backoff.delay(self.attempts);
+                        self.next_attempt = Instant::now() + delay;
+                        self.set_state(ConnectionState::Reconnecting {
+                            attempt: self.attempts,
+                            delay,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Open a new connection to the target.
+    fn open(&self) -> Result<Client<Req, Resp>, Error> {
+        match &self.target {
+            Target::Tcp(addrs) => self.builder.connect(addrs.as_slice()),
+            #[cfg(feature = "websocket")]
+            Target::Ws(url, codec) => self.builder.connect_ws_with(url, *codec),
+        }
+    }
+
+    /// Drop the current connection after it failed, a new one is attempted right away.
+    fn disconnect(&mut self, e: &Error) {
+        warn!("reconnecting client :: connection lost: {}", e);
+        self.client = None;
+        self.attempts = 0;
+        self.next_attempt = Instant::now();
+        self.set_state(ConnectionState::Disconnected);
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            if let Some(cb) = self.cb_state.as_mut() {
+                cb(&self.state);
+            }
+        }
+    }
+}
+
+/// Whether an error means the connection is no longer usable.
+fn is_disconnect(e: &Error) -> bool {
+    !matches!(e, Error::Timeout | Error::Codec(_))
+}
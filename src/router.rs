@@ -0,0 +1,128 @@
+use crate::handler::Handler;
+use crate::server::{Conn, Request};
+use log::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Handler for the messages of a single kind, also given the request if it is one.
+type Route<S,Req,Resp> = Box<dyn FnMut(&mut Conn<S,Resp>, Option<Request>, Req) + Send>;
+
+/// Tells the variant of a message apart, without its data.
+///
+/// With the `derive` feature, `#[derive(Discriminant)]` on an enum `Msg` generates a
+/// `MsgKind` enum with the same variants, without their fields, and implements this.
+pub trait Discriminant {
+    /// Identifies a variant.
+    type Kind: Copy + Eq + Hash + Debug;
+
+    /// Variant of this message.
+    fn kind(&self) -> Self::Kind;
+}
+
+/// Dispatches every message to the handler registered for its variant.
+///
+/// Routes for different parts of the protocol can be built apart, e.g. in different
+/// modules, and then put together with `merge`. Messages without a route go to the
+/// fallback, or are logged and dropped without one.
+///
+/// ```ignore
+/// let router = Router::<State, Msg>::new()
+///     .route(MsgKind::Add, |conn, req, msg| { ... })
+///     .route(MsgKind::Print, |conn, req, msg| { ... })
+///     .fallback_reply(|_kind| Msg::Err);
+/// server.serve(router);
+/// ```
+pub struct Router<S, Req: Discriminant, Resp = Req> {
+    routes: HashMap<Req::Kind, Route<S,Req,Resp>>,
+    fallback: Option<Route<S,Req,Resp>>,
+}
+
+impl<S, Req, Resp> Router<S, Req, Resp>
+where
+    Req: Discriminant + 'static,
+    Resp: Serialize + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// Handle the messages of the given kind, replacing any previous route for it.
+    ///
+    /// The handler is also given the request, when the message is one, to `Conn::reply`
+    /// to it.
+    pub fn route<F>(mut self, kind: Req::Kind, handler: F) -> Self
+    where
+        F: FnMut(&mut Conn<S,Resp>, Option<Request>, Req) + Send + 'static,
+    {
+        self.routes.insert(kind, Box::new(handler));
+        self
+    }
+
+    /// Handle the messages without a route.
+    pub fn fallback<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(&mut Conn<S,Resp>, Option<Request>, Req) + Send + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Answer the messages without a route with the message given for their kind, e.g. an
+    /// error saying they are not supported.
+    pub fn fallback_reply<F>(self, mut reply: F) -> Self
+    where
+        F: FnMut(Req::Kind) -> Resp + Send + 'static,
+    {
+        self.fallback(move |conn, request, msg| {
+            let _ = conn.respond(request.as_ref(), reply(msg.kind()));
+        })
+    }
+
+    /// Add the routes of another router, which replace the ones of this router for the same
+    /// kinds. The fallback of the other router is only used if this one has none.
+    pub fn merge(mut self, other: Self) -> Self {
+        self.routes.extend(other.routes);
+        if self.fallback.is_none() {
+            self.fallback = other.fallback;
+        }
+        self
+    }
+
+    /// Dispatch a message to its route, e.g. from another handler holding the router.
+    pub fn dispatch(&mut self, conn: &mut Conn<S,Resp>, request: Option<Request>, msg: Req) {
+        let kind = msg.kind();
+        match self.routes.get_mut(&kind).or(self.fallback.as_mut()) {
+            Some(route) => route(conn, request, msg),
+            None => warn!("{} :: no route for {:?}", conn.addr, kind),
+        }
+    }
+}
+
+impl<S, Req, Resp> Default for Router<S, Req, Resp>
+where
+    Req: Discriminant + 'static,
+    Resp: Serialize + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, Req, Resp> Handler<S, Req, Resp> for Router<S, Req, Resp>
+where
+    Req: Discriminant + 'static,
+    Resp: Serialize + 'static,
+{
+    fn on_message(&mut self, conn: &mut Conn<S,Resp>, msg: Req) {
+        self.dispatch(conn, None, msg);
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S,Resp>, request: Request, msg: Req) {
+        self.dispatch(conn, Some(request), msg);
+    }
+}
@@ -1,97 +1,341 @@
-use crate::pk::{self, RecvResult};
-use log::{info, warn};
+use crate::error::Error;
+use crate::handle::{Command, Deferred, ServerHandle};
+use crate::handler::{Handler, IntoResponse};
+#[cfg(feature = "prometheus")]
+use crate::metrics;
+use crate::middleware::{Chain, Flow, Middleware, Outbound};
+use crate::pk::{Header, RecvResult};
+use crate::poll::{Collect, Event};
+use crate::stats::{Closing, ConnStats, Counters, ServerStats};
+use crate::stream::Stream;
+#[cfg(feature = "tracing")]
+use crate::trace::TraceId;
+#[cfg(feature = "websocket")]
+use crate::ws::{self, Codec, Handshake};
+use log::{debug, info, warn};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::error::Error;
+use std::fmt;
 use std::marker::PhantomData;
-use std::net::{TcpListener, SocketAddr, TcpStream, Shutdown};
+use std::net::{TcpListener, SocketAddr};
+use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc;
+#[cfg(feature = "websocket")]
+use std::sync::mpsc::Sender;
+#[cfg(feature = "websocket")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Callback on a connection.
+type ConnCallback<S,Resp> = fn(&mut Conn<S,Resp>);
+/// Callback on a connection error.
+type ErrorCallback<S,Resp> = fn(&mut Conn<S,Resp>, Error);
+/// Callback on a timer of a connection.
+type TimerCallback<S,Resp> = fn(&mut Conn<S,Resp>, u64);
+/// Callback run periodically on every connection.
+type TickCallback<S,Resp> = Box<dyn FnMut(&mut [Conn<S,Resp>]) + Send>;
+/// Creates the state of each new connection, or rejects it.
+type StateFactory<S> = Box<dyn FnMut(&ConnInfo) -> Result<S, Reject> + Send>;
+/// Callback on a panic while handling a connection.
+type PanicCallback<S,Resp> = fn(&mut Conn<S,Resp>, &str);
+/// Callback on a connection message.
+type MessageCallback<S,Req,Resp> = fn(&mut Conn<S,Resp>, Req);
+/// Callback on a connection request.
+type RequestCallback<S,Req,Resp> = fn(&mut Conn<S,Resp>, Request, Req);
+/// Callback on a connection message or request, returning its response or an error.
+type ResultCallback<S,Req,Resp,E> = fn(&mut Conn<S,Resp>, Req) -> Result<Option<Resp>, E>;
+/// Callback on a connection message or request, returning what to respond.
+type RespondCallback<S,Req,Resp> = Box<dyn Fn(&mut Conn<S,Resp>, Req) -> Option<Resp> + Send>;
 
 /// Represents our server.
-pub struct Server<S,M> {
-    /// Receive new connections from the slave thread.
+///
+/// Clients send `Req` messages, and the server sends `Resp` messages back, which is the
+/// same type unless told otherwise.
+pub struct Server<S, Req, Resp = Req> {
+    /// Receive new connections from the slave threads.
     listener: Receiver<ConnInbound>,
+    /// Receive work from the handles.
+    commands: Receiver<Command<S,Resp>>,
+    /// Cloned into every new handle.
+    handle: ServerHandle<S,Resp>,
+    /// Handed to every new slave thread.
+    #[cfg(feature = "websocket")]
+    inbound: Sender<ConnInbound>,
     /// List of current connections.
-    conns: Vec<Conn<S,M>>,
-
-    /* connection callbacks */
-    cb_closed: Option<fn (&mut Conn<S,M>)>,
-    cb_closed_unexpected: Option<fn (&mut Conn<S,M>)>,
-    cb_connection: Option<fn (&mut Conn<S,M>)>,
-    cb_error: Option<fn (&mut Conn<S,M>, Box<dyn Error>)>,
-    cb_message: Option<fn (&mut Conn<S,M>, M)>,
+    conns: Vec<Conn<S,Resp>>,
+    /// Callbacks used by `run`.
+    callbacks: Callbacks<S,Req,Resp>,
+    /// Run around the dispatch of every message, in order.
+    middleware: Vec<Box<dyn Middleware<S,Req,Resp> + Send + Sync>>,
+    /// Built from the middlewares once the server starts handling connections.
+    chain: Option<Arc<Chain<S,Req,Resp>>>,
+    /// Id of the next connection.
+    next_id: u64,
+    /// Periodic callbacks.
+    ticks: Vec<Tick<S,Resp>>,
+    /// Creates the state of new connections.
+    factory: StateFactory<S>,
+    /// What to do with connections sending messages that fail to be decoded.
+    policy: ErrorPolicy,
+}
+
+/// What to do with a connection after a message from it fails to be decoded.
+///
+/// Every message is a frame of its own, so a bad one can be skipped without losing track
+/// of the next ones. Transport errors always close the connection.
+///
+/// To decide case by case, skip every error and call `Conn::close` from the error callback
+/// when the connection should go.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Close the connection on the first bad message (the default).
+    #[default]
+    Close,
+    /// Skip bad messages, after running the error callback.
+    Skip,
+    /// Skip bad messages, but close the connection on the given amount of them.
+    CloseAfter(u32),
+}
+
+/// Callback run every interval.
+struct Tick<S,Resp> {
+    interval: Duration,
+    /// When it runs next.
+    next: Instant,
+    cb: TickCallback<S,Resp>,
+}
+
+/// Handler made of the callbacks given to the server.
+struct Callbacks<S,Req,Resp> {
+    cb_closed: Option<ConnCallback<S,Resp>>,
+    cb_closed_unexpected: Option<ConnCallback<S,Resp>>,
+    cb_connection: Option<ConnCallback<S,Resp>>,
+    cb_error: Option<ErrorCallback<S,Resp>>,
+    cb_message: Option<MessageCallback<S,Req,Resp>>,
+    cb_panic: Option<PanicCallback<S,Resp>>,
+    cb_request: Option<RequestCallback<S,Req,Resp>>,
+    cb_respond: Option<RespondCallback<S,Req,Resp>>,
+    cb_timer: Option<TimerCallback<S,Resp>>,
 }
 
 /// Server side representation of a client connection.
-pub struct Conn<S,M> {
-    /// Tcp stream to client.
-    stream: TcpStream,
-    /// Type of the messages.
-    msg_type: PhantomData<M>,
+pub struct Conn<S,Resp> {
+    /// Stream to client.
+    stream: Stream,
+    /// Type of the messages sent to the client.
+    msg_type: PhantomData<Resp>,
+    /// Middlewares every sent message goes through.
+    outbound: Arc<dyn Outbound<S,Resp> + Send + Sync>,
+    /// Largest frame to send, as limited by the middlewares.
+    max_frame: Option<u64>,
     /// Connection state.
     state: Box<S>,
+    /// Identifies the connection within its server.
+    id: ConnId,
+    /// Reaches the connection later, for deferred responses.
+    handle: ServerHandle<S,Resp>,
     /// Wether the connection has been set as should close.
     should_close: bool,
+    /// Amount of messages that failed to be decoded.
+    decode_errors: u32,
+    /// Pending timers, with their event.
+    timers: Vec<(Instant, u64)>,
+    /// Run once the connection is dropped by the server.
+    on_drop: Vec<Box<dyn FnOnce() + Send>>,
+    /// Counters of the connection.
+    stats: ConnStats,
+    /// Why the connection was closed, if it was.
+    closed_by: Option<Closing>,
+    /// Error of a failed write, for the handler.
+    failed: Option<Error>,
+    /// Who the client is, once known.
+    identity: Option<String>,
+    /// Trace id of the message being handled.
+    trace: Option<u64>,
+    /// Span every log of the connection is in.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 
     /// Address of client connection.
     pub addr: SocketAddr,
 }
 
+/// Identifies a connection within its server, to reach it with `Server::send`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnId(u64);
+
+/// Identifies a request received from a client, to reply to it with `Conn::reply`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Request {
+    /// Id chosen by the client.
+    id: u64,
+    /// Trace id chosen by the client.
+    trace: Option<u64>,
+}
+
+/// What is known about a new connection, to create its state with `Server::state_factory`.
+pub struct ConnInfo {
+    id: ConnId,
+    addr: SocketAddr,
+    #[cfg(feature = "websocket")]
+    handshake: Option<Handshake>,
+}
+
+/// Refuses a new connection, when creating its state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reject {
+    reason: String,
+}
+
 /// Represent new inbound connections.
 struct ConnInbound {
-    stream: TcpStream,
+    stream: Stream,
     addr: SocketAddr,
+    /// Request of the websocket handshake, if any.
+    #[cfg(feature = "websocket")]
+    handshake: Option<Handshake>,
 }
 
-impl<S, M> Server<S, M>
+impl<S, Req, Resp> Server<S, Req, Resp>
 where
     S: Default,
-    M: Serialize + DeserializeOwned,
+    Req: DeserializeOwned,
+    Resp: Serialize,
 {
     /// Create a new server by binding to a listening TCP port.
-    pub fn bind(addr: &str) -> Result<Self, Box<dyn Error>> {
+    ///
+    /// The state of every connection starts as its default, see `bind_with` for states
+    /// created from the connection.
+    pub fn bind(addr: &str) -> Result<Self, Error> {
+        Self::bind_with(addr, |_| Ok(S::default()))
+    }
+}
+
+impl<S, Req, Resp> Server<S, Req, Resp>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    /// Create a new server by binding to a listening TCP port, creating the state of every
+    /// connection with the given function, see `state_factory`.
+    pub fn bind_with<F>(addr: &str, factory: F) -> Result<Self, Error>
+    where
+        F: FnMut(&ConnInfo) -> Result<S, Reject> + Send + 'static,
+    {
         // create slave thread with blocking tcp listener
         let sock = TcpListener::bind(addr)?;
         sock.set_nonblocking(false)?;
         let (tx, rx) = mpsc::channel::<ConnInbound>();
-        // TODO: handle the child thread somewhere
+        let (handle, commands) = mpsc::channel();
+        #[cfg(feature = "websocket")]
+        let inbound = tx.clone();
+        // stops once the server is gone, which frees the port
         thread::spawn(move || loop {
             // listen for possible new connections
-            match sock.accept() {
-                Ok((stream, addr)) => { // new connection
-                    // TODO: handle channel unwrap
-                    tx.send(ConnInbound { stream, addr }).unwrap();
-                }
-                Err(e) => { // error
-                    // TODO: panic for now
-                    panic!("err: {}", e);
+            let (stream, addr) = match sock.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("listener :: failed to accept: {}", e);
+                    // e.g. out of file descriptors, which takes a while to resolve
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
                 }
+            };
+            let inbound = ConnInbound {
+                stream: Stream::tcp(stream),
+                addr,
+                #[cfg(feature = "websocket")]
+                handshake: None,
+            };
+            if let Err(mpsc::SendError(mut inbound)) = tx.send(inbound) {
+                debug!("{} :: server is gone, dropping connection", addr);
+                attempt_shutdown(&mut inbound.stream);
+                break;
             }
         });
 
         // return the new server
-        let conns: Vec<Conn<S,M>> = Vec::new();
+        let conns: Vec<Conn<S,Resp>> = Vec::new();
         Ok(Self {
             listener: rx,
+            commands,
+            handle: ServerHandle::new(handle, 4),
+            #[cfg(feature = "websocket")]
+            inbound,
             conns,
             /* connection callbacks */
-            cb_closed: None,
-            cb_closed_unexpected: None,
-            cb_connection: None,
-            cb_error: None,
-            cb_message: None,
+            callbacks: Callbacks::default(),
+            middleware: Vec::new(),
+            chain: None,
+            next_id: 0,
+            ticks: Vec::new(),
+            factory: Box::new(factory),
+            policy: ErrorPolicy::Close,
         })
     }
 
+    /// Additionally accept WebSocket connections on the given address.
+    ///
+    /// Each binary WebSocket message carries a single frame, and the resulting connections
+    /// are handled just like tcp ones, with the same callbacks.
+    #[cfg(feature = "websocket")]
+    pub fn bind_ws(self, addr: &str) -> Result<Self, Error> {
+        self.bind_ws_with(addr, Codec::Bincode)
+    }
+
+    /// Same as `bind_ws`, but encoding messages with the given codec.
+    #[cfg(feature = "websocket")]
+    pub fn bind_ws_with(self, addr: &str, codec: Codec) -> Result<Self, Error> {
+        // create another slave thread, handshakes are done on their own threads so a slow
+        // client cannot hold back the rest
+        let sock = TcpListener::bind(addr)?;
+        sock.set_nonblocking(false)?;
+        let tx = self.inbound.clone();
+        // set by the handshake threads once the server is gone, to stop accepting
+        let gone = Arc::new(AtomicBool::new(false));
+        thread::spawn(move || while !gone.load(Ordering::Relaxed) {
+            let (stream, addr) = match sock.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("websocket listener :: failed to accept: {}", e);
+                    // e.g. out of file descriptors, which takes a while to resolve
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            let gone = gone.clone();
+            thread::spawn(move || match ws::accept(stream) {
+                Ok((ws, handshake)) => {
+                    let inbound = ConnInbound {
+                        stream: Stream::Ws(ws, codec),
+                        addr,
+                        handshake: Some(handshake),
+                    };
+                    if let Err(mpsc::SendError(mut inbound)) = tx.send(inbound) {
+                        debug!("{} :: server is gone, dropping websocket connection", addr);
+                        attempt_shutdown(&mut inbound.stream);
+                        gone.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(e) => {
+                    warn!("{} :: websocket handshake failed: {}", addr, e);
+                }
+            });
+        });
+        Ok(self)
+    }
+
     /// Setup a callback for closed connections.
     ///
     /// This callback will only _ever_ be run, when the client terminates the connection
     /// to the server.
-    pub fn on_closed(mut self, cb: fn(&mut Conn<S,M>)) -> Self {
-        self.cb_closed = Some(cb);
+    pub fn on_closed(mut self, cb: fn(&mut Conn<S,Resp>)) -> Self {
+        self.callbacks.cb_closed = Some(cb);
         self
     }
 
@@ -99,8 +343,8 @@ where
     ///
     /// This callback will be run when the connection is closed by the client when the
     /// server is receiving a message.
-    pub fn on_closed_unexpected(mut self, cb: fn(&mut Conn<S,M>)) -> Self {
-        self.cb_closed_unexpected = Some(cb);
+    pub fn on_closed_unexpected(mut self, cb: fn(&mut Conn<S,Resp>)) -> Self {
+        self.callbacks.cb_closed_unexpected = Some(cb);
         self
     }
 
@@ -108,25 +352,118 @@ where
     ///
     /// This callback will be run when the server stablishes a new connection with a client,
     /// _before_ any messages are recevied.
-    pub fn on_connection(mut self, cb: fn (&mut Conn<S,M>)) -> Self {
-        self.cb_connection = Some(cb);
+    pub fn on_connection(mut self, cb: fn (&mut Conn<S,Resp>)) -> Self {
+        self.callbacks.cb_connection = Some(cb);
         self
     }
 
     /// Setup a callback for connection errors.
     ///
     /// This callback will be run whenever there is an error attempting to get the next
-    /// message from a connection, e.g. a bad message that fails to be deserialized.
-    pub fn on_error(mut self, cb: fn(&mut Conn<S,M>, Box<dyn Error>)) -> Self {
-        self.cb_error = Some(cb);
+    /// message from a connection, e.g. a bad message that fails to be deserialized. The
+    /// connection is closed after it, unless the error policy skips the bad message.
+    pub fn on_error(mut self, cb: fn(&mut Conn<S,Resp>, Error)) -> Self {
+        self.callbacks.cb_error = Some(cb);
+        self
+    }
+
+    /// Create the state of every new connection with the given function, from what is
+    /// known about it, e.g. its address or websocket handshake.
+    ///
+    /// Returning an error rejects the connection, which is closed right away, without
+    /// running any callback.
+    pub fn state_factory<F>(mut self, factory: F) -> Self
+    where
+        F: FnMut(&ConnInfo) -> Result<S, Reject> + Send + 'static,
+    {
+        self.factory = Box::new(factory);
+        self
+    }
+
+    /// Choose what to do with connections sending messages that fail to be decoded.
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.policy = policy;
         self
     }
 
     /// Setup a calback for each received message.
     ///
     /// This is the main callbcak, which is run every time a connection sends a new message.
-    pub fn on_message(mut self, cb: fn (&mut Conn<S,M>, M)) -> Self {
-        self.cb_message = Some(cb);
+    pub fn on_message(mut self, cb: fn (&mut Conn<S,Resp>, Req)) -> Self {
+        self.callbacks.cb_message = Some(cb);
+        self
+    }
+
+    /// Setup a callback for panics in the other callbacks.
+    ///
+    /// A panic while handling a connection only closes that connection, then this callback
+    /// is run with the panic message, and the server keeps serving the other ones.
+    pub fn on_panic(mut self, cb: fn(&mut Conn<S,Resp>, &str)) -> Self {
+        self.callbacks.cb_panic = Some(cb);
+        self
+    }
+
+    /// Setup a callback for the timers of connections.
+    ///
+    /// This callback will be run with the event given to `Conn::schedule_after`, once its
+    /// time comes, on the same thread as the other callbacks.
+    pub fn on_timer(mut self, cb: fn(&mut Conn<S,Resp>, u64)) -> Self {
+        self.callbacks.cb_timer = Some(cb);
+        self
+    }
+
+    /// Run a callback on every connection each interval, e.g. to save their state.
+    ///
+    /// The callback runs on the same thread as the other callbacks, between the messages,
+    /// so it can be late, but never runs twice for a missed interval.
+    pub fn on_tick<F>(mut self, interval: Duration, cb: F) -> Self
+    where
+        F: FnMut(&mut [Conn<S,Resp>]) + Send + 'static,
+    {
+        self.ticks.push(Tick { interval, next: Instant::now() + interval, cb: Box::new(cb) });
+        self
+    }
+
+    /// Setup a callback for each received request.
+    ///
+    /// Requests are messages sent with `Client::request`, the client waits for the reply
+    /// sent with `Conn::reply`. Without this callback, requests are handed to the message
+    /// callback instead, and cannot be replied to.
+    pub fn on_request(mut self, cb: fn (&mut Conn<S,Resp>, Request, Req)) -> Self {
+        self.callbacks.cb_request = Some(cb);
+        self
+    }
+
+    /// Add a middleware, run around the dispatch of every message and every send.
+    ///
+    /// Inbound messages go through the middlewares in the order they were added, before
+    /// reaching the callbacks (or handler), and outbound messages in the reverse order.
+    /// Middlewares must be added before the server starts serving or polling.
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware<S,Req,Resp> + Send + Sync + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Setup a callback answering each received message, and request, with its return value.
+    ///
+    /// `Ok(Some(resp))` is sent back, as the reply to a request, `Ok(None)` sends nothing,
+    /// and errors are turned into their response with `IntoResponse`. A response that fails
+    /// to send goes to the error callback, and closes the connection if writing it failed.
+    /// When set, this is used instead of the message and request callbacks.
+    pub fn on_respond<E>(mut self, cb: ResultCallback<S,Req,Resp,E>) -> Self
+    where
+        S: 'static,
+        Req: 'static,
+        Resp: 'static,
+        E: IntoResponse<Resp> + 'static,
+    {
+        self.callbacks.cb_respond = Some(Box::new(move |conn, msg| match cb(conn, msg) {
+            Ok(reply) => reply,
+            Err(e) => Some(e.into_response()),
+        }));
         self
     }
 
@@ -134,136 +471,834 @@ where
     ///
     /// At this moment, the server starts adding inbound connections and handling them,
     /// by using the callbacks given during its creation.
-    pub fn run(mut self) -> ! {
+    pub fn run(mut self) -> !
+    where
+        S: 'static,
+        Req: 'static,
+        Resp: 'static,
+    {
+        let callbacks = mem::take(&mut self.callbacks);
+        self.serve(callbacks)
+    }
+
+    /// Run the server, handling the events of every connection with the given handler
+    /// instead of the callbacks.
+    pub fn serve<H>(mut self, mut handler: H) -> !
+    where
+        H: Handler<S, Req, Resp>,
+        S: 'static,
+        Req: 'static,
+        Resp: 'static,
+    {
         /* this loop will run forever */
         loop {
-            // check for new connections from slave thread
-            // TODO: handle try_recv errors
-            /* we use connection callback here */
-            while let Ok(inbound) = self.listener.try_recv() {
-                let mut conn = Conn::new(inbound);
-                info!("{} :: inbound", conn.addr);
-                if let Some(cb) = self.cb_connection {
-                    cb(&mut conn);
-                }
-                self.conns.push(conn);
+            self.turn(&mut handler);
+        }
+    }
+
+    /// Wait up to the given time for events from the connections, without any callbacks.
+    ///
+    /// This lets the caller drive the server from its own loop, e.g. once per frame with
+    /// a zero timeout, replying to connections through `send`, `reply`, or `conn_mut`.
+    /// Returns as soon as there are events, or when the time runs out, with no events.
+    pub fn poll(&mut self, timeout: Duration) -> Vec<Event<Req>>
+    where
+        S: 'static,
+        Req: 'static,
+        Resp: 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut events = Vec::new();
+        loop {
+            self.turn(&mut Collect::new(&mut events));
+            let now = Instant::now();
+            if !events.is_empty() || now >= deadline {
+                return events;
+            }
+            thread::sleep((deadline - now).min(Duration::from_millis(1)));
+        }
+    }
+
+    /// Set the amount of threads running the jobs given to `Conn::spawn` and
+    /// `ServerHandle::spawn`, 4 by default.
+    pub fn workers(self, workers: usize) -> Self {
+        self.handle.workers(workers);
+        self
+    }
+
+    /// Serve the metrics of the server on the given address, in the Prometheus text format,
+    /// at `/metrics`.
+    ///
+    /// The metrics are read from counters updated by the server loop, which scrapes never
+    /// hold back.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(self, addr: &str) -> Result<Self, Error> {
+        metrics::serve(addr, self.handle.counters().clone())?;
+        Ok(self)
+    }
+
+    /// Snapshot of the counters of the server, also available through its handles.
+    pub fn stats(&self) -> ServerStats {
+        self.handle.stats()
+    }
+
+    /// Handle to reach the connections from other threads, while the server runs.
+    pub fn handle(&self) -> ServerHandle<S,Resp> {
+        self.handle.clone()
+    }
+
+    /// Send a message to a connection.
+    pub fn send(&mut self, id: ConnId, msg: Resp) -> Result<(), Error> {
+        self.conn_mut(id).ok_or(Error::Closed)?.send(msg)
+    }
+
+    /// Reply to a request from a connection.
+    pub fn reply(&mut self, id: ConnId, request: &Request, msg: Resp) -> Result<(), Error> {
+        self.conn_mut(id).ok_or(Error::Closed)?.reply(request, msg)
+    }
+
+    /// Close a connection, which is dropped on the next poll, with `CloseReason::Server`.
+    pub fn close(&mut self, id: ConnId) -> Result<(), Error> {
+        self.conn_mut(id).ok_or(Error::Closed)?.close()
+    }
+
+    /// Connection with the given id, if it is still open.
+    pub fn conn(&self, id: ConnId) -> Option<&Conn<S,Resp>> {
+        self.conns.iter().find(|conn| conn.id == id && !conn.should_close)
+    }
+
+    /// Connection with the given id, if it is still open.
+    pub fn conn_mut(&mut self, id: ConnId) -> Option<&mut Conn<S,Resp>> {
+        self.conns.iter_mut().find(|conn| conn.id == id && !conn.should_close)
+    }
+
+    /// Current connections.
+    pub fn conns(&mut self) -> impl Iterator<Item = &mut Conn<S,Resp>> {
+        self.conns.iter_mut().filter(|conn| !conn.should_close)
+    }
+
+    /// Accept new connections and handle the pending messages of every connection once.
+    fn turn<H>(&mut self, handler: &mut H)
+    where
+        H: Handler<S, Req, Resp>,
+        S: 'static,
+        Req: 'static,
+        Resp: 'static,
+    {
+        if self.chain.is_none() {
+            self.chain = Some(Arc::new(Chain::new(mem::take(&mut self.middleware))));
+        }
+        let chain = self.chain.clone().unwrap();
+
+        // run the work queued through the handles
+        while let Ok(command) = self.commands.try_recv() {
+            if panic::catch_unwind(AssertUnwindSafe(|| command(&mut self.conns))).is_err() {
+                warn!("panicked running a command from a handle");
+            }
+        }
+        // run the ticks that are due
+        let now = Instant::now();
+        for tick in self.ticks.iter_mut().filter(|tick| tick.next <= now) {
+            tick.next += tick.interval;
+            // skip the intervals missed while it was late
+            if tick.next <= now {
+                tick.next = now + tick.interval;
+            }
+            let conns = &mut self.conns;
+            if panic::catch_unwind(AssertUnwindSafe(|| (tick.cb)(conns))).is_err() {
+                warn!("panicked running a tick");
             }
+        }
+        // check for new connections from slave thread
+        // TODO: handle try_recv errors
+        /* we use connection callback here */
+        while let Ok(mut inbound) = self.listener.try_recv() {
+            let id = ConnId(self.next_id);
+            self.next_id += 1;
+            let info = ConnInfo {
+                id,
+                addr: inbound.addr,
+                #[cfg(feature = "websocket")]
+                handshake: inbound.handshake.take(),
+            };
+            let factory = &mut self.factory;
+            let counters = self.handle.counters();
+            let state = match panic::catch_unwind(AssertUnwindSafe(|| factory(&info))) {
+                Ok(Ok(state)) => state,
+                Ok(Err(reject)) => {
+                    info!("{} :: rejected: {}", inbound.addr, reject);
+                    attempt_shutdown(&mut inbound.stream);
+                    Counters::add(&counters.rejected, 1);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("{} :: panicked creating its state", inbound.addr);
+                    attempt_shutdown(&mut inbound.stream);
+                    Counters::add(&counters.rejected, 1);
+                    continue;
+                }
+            };
+            Counters::add(&counters.accepted, 1);
+            Counters::add(&counters.active, 1);
+            let mut conn = Conn::new(id, inbound, state, chain.clone(), self.handle.clone());
+            #[cfg(feature = "tracing")]
+            let span = conn.span.clone().entered();
+            info!("{} :: inbound", conn.addr);
+            guard(handler, &mut conn, |handler, conn| handler.on_connection(conn));
+            #[cfg(feature = "tracing")]
+            drop(span);
+            self.conns.push(conn);
+        }
 
-            /* handle current list of connections
-             * TODO: use thread pool for better performance ?
-             */
-            for mut conn in self.conns.iter_mut() {
-                /* skip closed connections */
-                if conn.should_close {  continue; }
-
-                match conn.try_receive() {
-                    /* succesfully received a message */
-                    Ok(RecvResult::Some(msg)) => {
-                        info!("{} :: message", conn.addr);
-                        if let Some(cb) = self.cb_message {
-                            cb(&mut conn, msg);
+        /* handle current list of connections
+         * TODO: use thread pool for better performance ?
+         */
+        for conn in self.conns.iter_mut() {
+            /* skip closed connections */
+            if conn.should_close {  continue; }
+            #[cfg(feature = "tracing")]
+            let _span = conn.span.clone().entered();
+
+            match conn.try_receive::<Req>() {
+                /* succesfully received a message */
+                Ok(RecvResult::Some((header, msg))) => guard(handler, conn, |handler, conn| {
+                    let request = header.id.map(|id| Request { id, trace: header.trace });
+                    conn.trace = header.trace;
+                    #[cfg(feature = "tracing")]
+                    let span = tracing::info_span!(
+                        "dispatch",
+                        request = request.map(|request| request.id),
+                        trace = header.trace.map(|trace| tracing::field::display(TraceId(trace))),
+                        elapsed_us = tracing::field::Empty,
+                    ).entered();
+                    #[cfg(any(feature = "prometheus", feature = "tracing"))]
+                    let started = Instant::now();
+                    let flow = chain.dispatch(conn, request, msg, |conn, msg| match request {
+                        Some(request) => {
+                            info!("{} :: request {}", conn.addr, request.id);
+                            handler.on_request(conn, request, msg);
                         }
-                    }
-                    /* client closed connection */
-                    Ok(RecvResult::Closed) => {
-                        info!("{} :: closed", conn.addr);
-                        attempt_shutdown(&mut conn.stream);
-                        if let Some(cb) = self.cb_closed {
-                            cb(conn);
+                        None => {
+                            info!("{} :: message", conn.addr);
+                            handler.on_message(conn, msg);
                         }
-                        conn.should_close = true;
+                    });
+                    #[cfg(feature = "prometheus")]
+                    conn.handle.counters().metrics.handled(started.elapsed());
+                    #[cfg(feature = "tracing")]
+                    {
+                        span.record("elapsed_us", started.elapsed().as_micros() as u64);
+                        tracing::debug!("handled");
                     }
-                    /* client remains silent */
-                    /*
-                     * TODO: implement some sort of timeout system
-                     */
-                    Ok(RecvResult::None) => {}
-                    /* client closed unexpectedly, terminates connection */
-                    Ok(RecvResult::ClosedWrongly) => {
-                        warn!("{} :: closed unexepectedly", conn.addr);
-                        attempt_shutdown(&mut conn.stream);
-                        if let Some(cb) = self.cb_closed_unexpected {
-                            cb(conn);
+                    match flow {
+                        Flow::Next(()) | Flow::Drop => {}
+                        /* answered by a middleware */
+                        Flow::Reply(reply) => {
+                            if let Err(e) = conn.respond(request.as_ref(), reply) {
+                                if !conn.is_closing() {
+                                    handler.on_error(conn, e);
+                                }
+                            }
                         }
-                        conn.should_close = true;
-                    }
-                    /* any other error, terminates connection as well */
-                    Err(e) => {
-                        warn!("{} :: error: {}", conn.addr, e);
-                        attempt_shutdown(&mut conn.stream);
-                        if let Some(cb) = self.cb_error {
-                            cb(conn, e);
+                        /* rejected by a middleware, terminates connection */
+                        Flow::Reject(e) => {
+                            warn!("{} :: rejected: {}", conn.addr, e);
+                            conn.shut(Closing::Error);
+                            handler.on_error(conn, e);
                         }
-                        conn.should_close = true;
                     }
+                    conn.trace = None;
+                }),
+                /* client closed connection */
+                Ok(RecvResult::Closed) => {
+                    info!("{} :: closed", conn.addr);
+                    conn.shut(Closing::Client);
+                    guard(handler, conn, |handler, conn| handler.on_closed(conn));
+                }
+                /* client remains silent */
+                /*
+                 * TODO: implement some sort of timeout system
+                 */
+                Ok(RecvResult::None) => {}
+                /* client closed unexpectedly, terminates connection */
+                Ok(RecvResult::ClosedWrongly) => {
+                    warn!("{} :: closed unexepectedly", conn.addr);
+                    conn.shut(Closing::Unexpected);
+                    guard(handler, conn, |handler, conn| handler.on_closed_unexpected(conn));
+                }
+                /* bad message, the policy decides if we skip it */
+                Err(e) if e.is_decode() && self.policy.skips(conn.decode_errors + 1) => {
+                    conn.decode_errors += 1;
+                    warn!("{} :: skipped bad message: {}", conn.addr, e);
+                    guard(handler, conn, |handler, conn| handler.on_error(conn, e));
+                }
+                /* any other error, terminates connection as well */
+                Err(e) => {
+                    if e.is_decode() {
+                        conn.decode_errors += 1;
+                    }
+                    warn!("{} :: error: {}", conn.addr, e);
+                    conn.shut(Closing::Error);
+                    guard(handler, conn, |handler, conn| handler.on_error(conn, e));
                 }
             }
-            self.conns.retain(|conn| {
-                !conn.should_close
-            });
+
+            /* fire the timers that are due */
+            while let Some(event) = conn.due_timer(now) {
+                if conn.should_close { break; }
+                guard(handler, conn, |handler, conn| handler.on_timer(conn, event));
+            }
         }
+        /* report the failed sends and the closes by the server, before dropping them */
+        for conn in self.conns.iter_mut() {
+            let failed = conn.failed.take();
+            if failed.is_none() && conn.closed_by != Some(Closing::Server) {
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            let _span = conn.span.clone().entered();
+            match failed {
+                Some(e) => guard(handler, conn, |handler, conn| handler.on_error(conn, e)),
+                None => guard(handler, conn, |handler, conn| handler.on_closed_by_server(conn)),
+            }
+        }
+        self.conns.retain(|conn| {
+            !conn.should_close
+        });
     }
 }
 
-impl<S,M> Conn<S,M>
+impl<S,Req,Resp> Default for Callbacks<S,Req,Resp> {
+    fn default() -> Self {
+        Self {
+            cb_closed: None,
+            cb_closed_unexpected: None,
+            cb_connection: None,
+            cb_error: None,
+            cb_message: None,
+            cb_panic: None,
+            cb_request: None,
+            cb_respond: None,
+            cb_timer: None,
+        }
+    }
+}
+
+impl<S,Req,Resp> Callbacks<S,Req,Resp>
 where
-    S: Default,
-    M: Serialize + DeserializeOwned
+    Resp: Serialize,
+{
+    /// Run the respond callback, if any, sending back what it returns.
+    fn respond(&mut self, conn: &mut Conn<S,Resp>, request: Option<Request>, msg: Req) {
+        let reply = match self.cb_respond.as_ref() {
+            Some(cb) => cb(conn, msg),
+            None => return,
+        };
+        if let Some(reply) = reply {
+            /* failed writes close the connection and are reported after the callback */
+            if let Err(e) = conn.respond(request.as_ref(), reply) {
+                if !conn.is_closing() {
+                    self.on_error(conn, e);
+                }
+            }
+        }
+    }
+}
+
+impl<S,Req,Resp> Handler<S,Req,Resp> for Callbacks<S,Req,Resp>
+where
+    Resp: Serialize,
 {
-    /// Create a new connection from its tcp stream and socket address.
-    /// Its initial states will be generates as per its implementation of the
-    /// Default trait.
-    fn new(inbound: ConnInbound) -> Self {
+    fn on_connection(&mut self, conn: &mut Conn<S,Resp>) {
+        if let Some(cb) = self.cb_connection {
+            cb(conn);
+        }
+    }
+
+    fn on_message(&mut self, conn: &mut Conn<S,Resp>, msg: Req) {
+        if self.cb_respond.is_some() {
+            self.respond(conn, None, msg);
+        } else if let Some(cb) = self.cb_message {
+            cb(conn, msg);
+        }
+    }
+
+    fn on_request(&mut self, conn: &mut Conn<S,Resp>, request: Request, msg: Req) {
+        if self.cb_respond.is_some() {
+            self.respond(conn, Some(request), msg);
+        } else if let Some(cb) = self.cb_request {
+            cb(conn, request, msg);
+        } else if let Some(cb) = self.cb_message {
+            cb(conn, msg);
+        }
+    }
+
+    fn on_closed(&mut self, conn: &mut Conn<S,Resp>) {
+        if let Some(cb) = self.cb_closed {
+            cb(conn);
+        }
+    }
+
+    fn on_closed_unexpected(&mut self, conn: &mut Conn<S,Resp>) {
+        if let Some(cb) = self.cb_closed_unexpected {
+            cb(conn);
+        }
+    }
+
+    fn on_error(&mut self, conn: &mut Conn<S,Resp>, e: Error) {
+        if let Some(cb) = self.cb_error {
+            cb(conn, e);
+        }
+    }
+
+    fn on_panic(&mut self, conn: &mut Conn<S,Resp>, msg: &str) {
+        if let Some(cb) = self.cb_panic {
+            cb(conn, msg);
+        }
+    }
+
+    fn on_timer(&mut self, conn: &mut Conn<S,Resp>, event: u64) {
+        if let Some(cb) = self.cb_timer {
+            cb(conn, event);
+        }
+    }
+}
+
+impl<S,Resp> Conn<S,Resp>
+where
+    Resp: Serialize
+{
+    /// Create a new connection from its tcp stream and socket address, with its initial
+    /// state.
+    fn new(
+        id: ConnId,
+        inbound: ConnInbound,
+        state: S,
+        outbound: Arc<dyn Outbound<S,Resp> + Send + Sync>,
+        handle: ServerHandle<S,Resp>,
+    ) -> Self {
+        let (max_in, max_out) = outbound.frame_limits();
+        let mut stream = inbound.stream;
+        if let Some(max) = max_in {
+            stream.set_max_frame(max);
+        }
         Self {
-            stream: inbound.stream,
+            id,
+            handle,
+            stream,
+            max_frame: max_out,
             addr: inbound.addr,
             msg_type: PhantomData,
-            state: Box::new(<S as Default>::default()),
+            outbound,
+            state: Box::new(state),
             should_close: false,
+            decode_errors: 0,
+            timers: Vec::new(),
+            on_drop: Vec::new(),
+            stats: ConnStats::new(),
+            closed_by: None,
+            failed: None,
+            identity: None,
+            trace: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "conn",
+                id = %id,
+                addr = %inbound.addr,
+                identity = tracing::field::Empty,
+            ),
         }
     }
 
     /// Attempt to receive and decode incoming packets in this connection.
-    fn try_receive(&mut self) -> Result<RecvResult<M>, Box<dyn Error>> {
-        pk::try_recv(&mut self.stream)
+    fn try_receive<Req>(&mut self) -> Result<RecvResult<(Header, Req)>, Error>
+    where
+        Req: DeserializeOwned
+    {
+        let (res, size) = self.stream.try_recv_sized();
+        let counters = self.handle.counters();
+        if size > 0 {
+            self.stats.bytes_in += size as u64;
+            self.stats.last_activity = SystemTime::now();
+            Counters::add(&counters.bytes_in, size as u64);
+        }
+        #[cfg(feature = "prometheus")]
+        if size > 0 {
+            counters.metrics.frame_in(size);
+        }
+        match &res {
+            Ok(RecvResult::Some(_)) => {
+                self.stats.messages_in += 1;
+                Counters::add(&counters.messages_in, 1);
+            }
+            Err(_e) => {
+                self.stats.errors += 1;
+                Counters::add(&counters.errors, 1);
+                #[cfg(feature = "prometheus")]
+                counters.metrics.error(_e);
+            }
+            Ok(_) => {}
+        }
+        res
     }
 
     /// Send a message back.
-    pub fn send(&mut self, msg: M) -> Result<(), Box<dyn Error>> {
-        match pk::send(msg, &mut self.stream) {
-            /* we failed to send the message */
+    ///
+    /// If the message is rejected before being written, by a middleware or for its size,
+    /// this fails with that error and the connection stays open. If writing it fails, the
+    /// connection is closed and the error goes to the error callback once the current one
+    /// returns, while this fails with `Error::Closed`, as does every send on a closed
+    /// connection.
+    pub fn send(&mut self, msg: Resp) -> Result<(), Error> {
+        self.send_with(Header { trace: self.trace, ..Header::default() }, msg)
+    }
+
+    /// Reply to a request, the client waiting on it gets this message.
+    pub fn reply(&mut self, request: &Request, msg: Resp) -> Result<(), Error> {
+        self.send_with(Header { id: None, reply_to: Some(request.id), trace: request.trace }, msg)
+    }
+
+    /// Reply to the request if there is one, or just send the message otherwise.
+    pub fn respond(&mut self, request: Option<&Request>, msg: Resp) -> Result<(), Error> {
+        match request {
+            Some(request) => self.reply(request, msg),
+            None => self.send(msg),
+        }
+    }
+
+    fn send_with(&mut self, header: Header, msg: Resp) -> Result<(), Error> {
+        if self.should_close {
+            return Err(Error::Closed);
+        }
+        let outbound = self.outbound.clone();
+        let encoded = outbound.outbound(self, msg)
+            .and_then(|msg| self.stream.encode(header, msg, self.max_frame));
+        let counters = self.handle.counters();
+        let res = match encoded {
+            /* rejected before anything was written, the stream is still fine */
+            Err(e) => {
+                warn!("{} :: message not sent: {}", self.addr, e);
+                self.send_failed(&e);
+                return Err(e);
+            }
+            Ok(encoded) => self.stream.write(encoded),
+        };
+        match res {
+            /* part of the message may be written, the stream cannot be used anymore */
             Err(e) => {
                 warn!("{} :: err send: {}", self.addr, e);
-                Err(e)
+                self.send_failed(&e);
+                self.shut(Closing::Error);
+                self.failed = Some(e);
+                Err(Error::Closed)
+            }
+            Ok(size) => {
+                self.stats.messages_out += 1;
+                self.stats.bytes_out += size as u64;
+                self.stats.last_activity = SystemTime::now();
+                Counters::add(&counters.messages_out, 1);
+                Counters::add(&counters.bytes_out, size as u64);
+                #[cfg(feature = "prometheus")]
+                counters.metrics.frame_out(size);
+                Ok(())
             }
-            Ok(_) => Ok(()),
         }
     }
 
+    /// Count a message that failed to be sent.
+    fn send_failed(&mut self, _e: &Error) {
+        let counters = self.handle.counters();
+        self.stats.errors += 1;
+        Counters::add(&counters.errors, 1);
+        #[cfg(feature = "prometheus")]
+        counters.metrics.error(_e);
+    }
+
+    /// Take a token to respond to the current message later, from any thread, without
+    /// holding back the server.
+    pub fn defer(&self, request: Option<Request>) -> Deferred<S,Resp>
+    where
+        S: 'static,
+        Resp: Send + 'static,
+    {
+        Deferred::new(self.handle.clone(), self.id, request)
+    }
+
+    /// Run slow work on the worker threads of the server, then respond with its result,
+    /// as the reply to the request if there is one.
+    ///
+    /// The job cannot reach the connection, so it takes what it needs from it beforehand.
+    pub fn spawn<F>(&self, request: Option<Request>, job: F)
+    where
+        F: FnOnce() -> Resp + Send + 'static,
+        S: 'static,
+        Resp: Send + 'static,
+    {
+        let deferred = self.defer(request);
+        // the job logs within the span of the message it answers
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        self.handle.spawn(move || {
+            #[cfg(feature = "tracing")]
+            let _span = span.entered();
+            // the server or the connection is gone otherwise
+            let _ = deferred.respond(job());
+        });
+    }
+
+    /// Run the timer callback with the given event after some time, on this connection.
+    pub fn schedule_after(&mut self, after: Duration, event: u64) {
+        self.timers.push((Instant::now() + after, event));
+    }
+
+    /// Cancel the pending timers with the given event.
+    pub fn cancel_timer(&mut self, event: u64) {
+        self.timers.retain(|&(_, e)| e != event);
+    }
+
+    /// Take the earliest timer that is due by the given time.
+    fn due_timer(&mut self, now: Instant) -> Option<u64> {
+        let (pos, _) = self.timers.iter().enumerate()
+            .filter(|(_, &(at, _))| at <= now)
+            .min_by_key(|(_, &(at, _))| at)?;
+        Some(self.timers.remove(pos).1)
+    }
+
+    /// Amount of messages from the client that failed to be decoded so far.
+    pub fn decode_errors(&self) -> u32 {
+        self.decode_errors
+    }
+
     /// Close the connection with the client.
-    pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.shut(Closing::Server);
+        Ok(())
+    }
+}
+
+impl<S,Resp> Conn<S,Resp> {
+    /// Identifies the connection within its server.
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    /// Handle to the server of this connection.
+    pub fn handle(&self) -> ServerHandle<S,Resp> {
+        self.handle.clone()
+    }
+
+    /// Snapshot of the counters of the connection.
+    pub fn stats(&self) -> ConnStats {
+        self.stats.clone()
+    }
+
+    /// Set who the client is, e.g. once authenticated, to tell its logs apart.
+    pub fn identify<I>(&mut self, identity: I)
+    where
+        I: Into<String>,
+    {
+        let identity = identity.into();
+        // recording the same value again would show it twice in some subscribers
+        if self.identity.as_deref() == Some(identity.as_str()) {
+            return;
+        }
+        #[cfg(feature = "tracing")]
+        self.span.record("identity", identity.as_str());
+        self.identity = Some(identity);
+    }
+
+    /// Who the client is, if set with `identify`.
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Trace id sent by the client with the message being handled, if any.
+    ///
+    /// Messages sent while handling it carry it back, as do the replies to its request.
+    pub fn trace(&self) -> Option<u64> {
+        self.trace
+    }
+
+    /// Span of the connection.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Shutdown the stream, and set the connection to be dropped, for the given reason
+    /// unless it was already closed.
+    fn shut(&mut self, by: Closing) {
         attempt_shutdown(&mut self.stream);
         self.should_close = true;
-        Ok(())
+        self.closed_by.get_or_insert(by);
+    }
+
+    /// Whether the connection was closed, to be dropped by the server.
+    pub(crate) fn is_closing(&self) -> bool {
+        self.should_close
+    }
+
+    /// Run the given function once the connection is dropped, however it was closed.
+    pub(crate) fn on_drop<F>(&mut self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.on_drop.push(Box::new(f));
+    }
+}
+
+impl<S,Resp> Drop for Conn<S,Resp> {
+    fn drop(&mut self) {
+        self.handle.counters().closed(self.closed_by.unwrap_or(Closing::Server));
+        for f in self.on_drop.drain(..) {
+            f();
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// Whether to skip a bad message, being the given one from its connection.
+    fn skips(&self, errors: u32) -> bool {
+        match *self {
+            ErrorPolicy::Close => false,
+            ErrorPolicy::Skip => true,
+            ErrorPolicy::CloseAfter(max) => errors < max,
+        }
+    }
+}
+
+impl fmt::Display for ConnId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+impl ConnInfo {
+    /// Id the connection gets, if accepted.
+    pub fn id(&self) -> ConnId {
+        self.id
+    }
+
+    /// Address of the client.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Path requested in the websocket handshake, for websocket connections.
+    #[cfg(feature = "websocket")]
+    pub fn path(&self) -> Option<&str> {
+        self.handshake.as_ref().map(|handshake| handshake.path.as_str())
+    }
+
+    /// Value of a header of the websocket handshake, by its case insensitive name, for
+    /// websocket connections.
+    #[cfg(feature = "websocket")]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.handshake.as_ref()?.header(name)
+    }
+}
+
+impl Reject {
+    /// Reject a connection for the given reason, which is logged.
+    pub fn new<R>(reason: R) -> Self
+    where
+        R: Into<String>,
+    {
+        Self { reason: reason.into() }
+    }
+
+    /// Why the connection was rejected.
+    pub fn reason(&self) -> &str {
+        &self.reason
     }
 }
 
-impl<S,M> Deref for Conn<S,M> {
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Request {
+    /// Id chosen by the client for this request.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Trace id chosen by the client, see `ClientBuilder::trace_ids`.
+    pub fn trace(&self) -> Option<u64> {
+        self.trace
+    }
+}
+
+impl<S,Resp> Deref for Conn<S,Resp> {
     type Target = S;
 
     fn deref(&self) -> &Self::Target {
-        &* self.state
+        &self.state
     }
 }
 
-impl<S,M> DerefMut for Conn<S,M> {
+impl<S,Resp> DerefMut for Conn<S,Resp> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.state
+        &mut self.state
     }
 }
 
-fn attempt_shutdown(stream: &mut TcpStream) {
-    if let Err(e) = stream.shutdown(Shutdown::Both) {
+/// Run user code on a connection, so that if it panics only this connection is lost,
+/// after reporting the panic to the handler.
+fn guard<S,Req,Resp,H,F>(handler: &mut H, conn: &mut Conn<S,Resp>, f: F)
+where
+    H: Handler<S,Req,Resp>,
+    F: FnOnce(&mut H, &mut Conn<S,Resp>),
+{
+    let payload = match panic::catch_unwind(AssertUnwindSafe(|| f(handler, conn))) {
+        Ok(()) => return,
+        Err(payload) => payload,
+    };
+    let msg = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    warn!("{} :: panicked: {}", conn.addr, msg);
+    conn.shut(Closing::Panic);
+    if panic::catch_unwind(AssertUnwindSafe(|| handler.on_panic(conn, msg))).is_err() {
+        warn!("{} :: panicked while handling a panic", conn.addr);
+    }
+}
+
+fn attempt_shutdown(stream: &mut Stream) {
+    if let Err(e) = stream.shutdown() {
         warn!("failed to shutdown stream: {}", e);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn late_tick_runs_once_for_missed_intervals() {
+        let interval = Duration::from_millis(50);
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let ticks = runs.clone();
+        let mut server = Server::<(), u32, u32>::bind("127.0.0.1:0").unwrap()
+            .on_tick(interval, move |_conns| {
+                let mut ticks = ticks.lock().unwrap();
+                ticks.push(Instant::now());
+                // the first run stalls the server for a few intervals
+                if ticks.len() == 1 {
+                    thread::sleep(interval * 3);
+                }
+            });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while runs.lock().unwrap().len() < 3 && Instant::now() < deadline {
+            server.poll(Duration::from_millis(1));
+        }
+
+        let runs = runs.lock().unwrap();
+        assert_eq!(runs.len(), 3);
+        // the late run makes up for the missed intervals, the next one waits a full interval
+        assert!(runs[2] - runs[1] >= interval - Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,138 @@
+#[cfg(feature = "prometheus")]
+use crate::metrics::Metrics;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Counters of a connection, see `Conn::stats`.
+#[derive(Clone, Debug)]
+pub struct ConnStats {
+    /// Messages received from the client.
+    pub messages_in: u64,
+    /// Messages sent to the client.
+    pub messages_out: u64,
+    /// Bytes received from the client, as frames.
+    pub bytes_in: u64,
+    /// Bytes sent to the client, as frames.
+    pub bytes_out: u64,
+    /// Messages that failed to be received or sent.
+    pub errors: u64,
+    /// When the connection was accepted.
+    pub connected_at: SystemTime,
+    /// When a message was last received or sent.
+    pub last_activity: SystemTime,
+}
+
+/// Counters of a whole server, see `Server::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections accepted.
+    pub accepted: u64,
+    /// Connections rejected by the state factory.
+    pub rejected: u64,
+    /// Connections currently open.
+    pub active: u64,
+    /// Connections terminated by their client.
+    pub closed: u64,
+    /// Connections closed by their client in the middle of a message.
+    pub closed_unexpected: u64,
+    /// Connections closed after an error.
+    pub closed_error: u64,
+    /// Connections closed after a panic while handling them.
+    pub closed_panic: u64,
+    /// Connections closed by the server, e.g. with `Conn::close`.
+    pub closed_server: u64,
+    /// Messages received.
+    pub messages_in: u64,
+    /// Messages sent.
+    pub messages_out: u64,
+    /// Bytes received, as frames.
+    pub bytes_in: u64,
+    /// Bytes sent, as frames.
+    pub bytes_out: u64,
+    /// Messages that failed to be received or sent.
+    pub errors: u64,
+}
+
+/// Why a connection was closed, for the counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Closing {
+    Client,
+    Unexpected,
+    Error,
+    Panic,
+    Server,
+}
+
+/// Counters of a server, updated from any thread.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub accepted: AtomicU64,
+    pub rejected: AtomicU64,
+    pub active: AtomicU64,
+    pub closed: AtomicU64,
+    pub closed_unexpected: AtomicU64,
+    pub closed_error: AtomicU64,
+    pub closed_panic: AtomicU64,
+    pub closed_server: AtomicU64,
+    pub messages_in: AtomicU64,
+    pub messages_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub errors: AtomicU64,
+    #[cfg(feature = "prometheus")]
+    pub metrics: Metrics,
+}
+
+impl ConnStats {
+    pub(crate) fn new() -> Self {
+        let now = SystemTime::now();
+        Self {
+            messages_in: 0,
+            messages_out: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            errors: 0,
+            connected_at: now,
+            last_activity: now,
+        }
+    }
+}
+
+impl Counters {
+    /// Add to a counter.
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a connection gone.
+    pub fn closed(&self, by: Closing) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        let counter = match by {
+            Closing::Client => &self.closed,
+            Closing::Unexpected => &self.closed_unexpected,
+            Closing::Error => &self.closed_error,
+            Closing::Panic => &self.closed_panic,
+            Closing::Server => &self.closed_server,
+        };
+        Self::add(counter, 1);
+    }
+
+    pub fn snapshot(&self) -> ServerStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ServerStats {
+            accepted: get(&self.accepted),
+            rejected: get(&self.rejected),
+            active: get(&self.active),
+            closed: get(&self.closed),
+            closed_unexpected: get(&self.closed_unexpected),
+            closed_error: get(&self.closed_error),
+            closed_panic: get(&self.closed_panic),
+            closed_server: get(&self.closed_server),
+            messages_in: get(&self.messages_in),
+            messages_out: get(&self.messages_out),
+            bytes_in: get(&self.bytes_in),
+            bytes_out: get(&self.bytes_out),
+            errors: get(&self.errors),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crate::error::Error;
+use crate::pk::{self, FrameReader, Header, RecvResult};
+#[cfg(feature = "websocket")]
+use crate::ws::{self, Codec};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+#[cfg(feature = "websocket")]
+use std::convert::TryFrom;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "websocket")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "websocket")]
+use tungstenite::WebSocket;
+
+/// Transport a connection communicates over.
+pub enum Stream {
+    /// Plain tcp stream, carrying length prefixed frames.
+    Tcp(TcpStream, FrameReader),
+    /// WebSocket, carrying one frame per message.
+    #[cfg(feature = "websocket")]
+    Ws(Box<WebSocket<TcpStream>>, Codec),
+}
+
+/// A message encoded for a stream, by `Stream::encode`.
+pub enum Encoded {
+    /// Length prefixed frame, for a tcp stream.
+    Frame(Vec<u8>),
+    /// Message for a WebSocket.
+    #[cfg(feature = "websocket")]
+    Ws(tungstenite::Message),
+}
+
+/// Reading half of a split stream.
+pub enum ReadHalf {
+    /// The stream could be cloned, reading does not get in the way of writing.
+    Owned(Stream),
+    /// The stream is shared with the writing half, reading polls so writers get a turn.
+    #[cfg(feature = "websocket")]
+    Shared(Arc<Mutex<Stream>>),
+}
+
+/// How long the reading half waits between looks at a shared stream.
+#[cfg(feature = "websocket")]
+const SHARED_POLL: Duration = Duration::from_millis(5);
+
+impl Stream {
+    /// Wrap a plain tcp stream.
+    pub fn tcp(stream: TcpStream) -> Self {
+        Stream::Tcp(stream, FrameReader::default())
+    }
+
+    /// Send a message (blocking).
+    pub fn send<M>(&mut self, header: Header, msg: M) -> Result<(), Error>
+    where
+        M: Serialize
+    {
+        self.send_sized(header, msg, None).map(|_| ())
+    }
+
+    /// Send a message (blocking) unless its frame is larger than the given size, returns
+    /// the amount of bytes sent.
+    pub fn send_sized<M>(&mut self, header: Header, msg: M, max: Option<u64>) -> Result<usize, Error>
+    where
+        M: Serialize
+    {
+        let encoded = self.encode(header, msg, max)?;
+        self.write(encoded)
+    }
+
+    /// Encode a message for this stream, failing if its frame is larger than the given size.
+    pub fn encode<M>(&self, header: Header, msg: M, max: Option<u64>) -> Result<Encoded, Error>
+    where
+        M: Serialize
+    {
+        match self {
+            Stream::Tcp(..) => {
+                let frame = pk::encode_frame(header, msg)?;
+                pk::check_size(frame.len() as u64, max)?;
+                Ok(Encoded::Frame(frame))
+            }
+            #[cfg(feature = "websocket")]
+            Stream::Ws(_, codec) => Ok(Encoded::Ws(ws::encode(header, msg, *codec, max)?)),
+        }
+    }
+
+    /// Write an encoded message (blocking), returns the amount of bytes sent.
+    ///
+    /// On failure, part of the message may have been written.
+    pub fn write(&mut self, encoded: Encoded) -> Result<usize, Error> {
+        match (self, encoded) {
+            (Stream::Tcp(stream, _), Encoded::Frame(frame)) => pk::write_frame(&frame, stream),
+            #[cfg(feature = "websocket")]
+            (Stream::Ws(ws, _), Encoded::Ws(msg)) => ws::write(msg, ws),
+            #[cfg(feature = "websocket")]
+            _ => unreachable!("message encoded for another stream"),
+        }
+    }
+
+    /// Fail on received frames larger than the given size, before buffering them.
+    pub fn set_max_frame(&mut self, max: u64) {
+        match self {
+            Stream::Tcp(_, reader) => reader.set_max(Some(max)),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ws, _) => ws.set_config(|config| {
+                let max = usize::try_from(max).unwrap_or(usize::MAX);
+                config.max_message_size = Some(max);
+                config.max_frame_size = Some(max);
+            }),
+        }
+    }
+
+    /// Receive a message (blocking), failing if it does not arrive within the timeout.
+    pub fn recv<M>(&mut self, timeout: Option<Duration>) -> Result<(Header, M), Error>
+    where
+        M: DeserializeOwned
+    {
+        match self {
+            Stream::Tcp(stream, reader) => reader.recv(stream, timeout),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ws, codec) => ws::recv(ws, *codec, timeout),
+        }
+    }
+
+    /// Attempt to receive a message, returns immediately if there is none.
+    ///
+    /// Also gives the amount of bytes received as messages, even when they fail to be
+    /// decoded.
+    pub fn try_recv_sized<M>(&mut self) -> (Result<RecvResult<(Header, M)>, Error>, usize)
+    where
+        M: DeserializeOwned
+    {
+        match self {
+            Stream::Tcp(stream, reader) => {
+                let res = reader.try_recv(stream);
+                (res, reader.take_consumed())
+            }
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ws, codec) => ws::try_recv_sized(ws, *codec),
+        }
+    }
+
+    /// Attempt to receive a message, blocking at most for the given duration.
+    pub fn recv_timeout<M>(&mut self, timeout: Duration)
+        -> Result<RecvResult<(Header, M)>, Error>
+    where
+        M: DeserializeOwned
+    {
+        match self {
+            Stream::Tcp(stream, reader) => reader.recv_timeout(stream, timeout),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ws, codec) => ws::recv_timeout(ws, *codec, timeout),
+        }
+    }
+
+    /// Split into a reading half, and a writing half that can be shared between threads.
+    pub fn split(self) -> Result<(ReadHalf, Arc<Mutex<Stream>>), Error> {
+        match self {
+            Stream::Tcp(stream, reader) => {
+                let writer = Stream::tcp(stream.try_clone()?);
+                Ok((ReadHalf::Owned(Stream::Tcp(stream, reader)), Arc::new(Mutex::new(writer))))
+            }
+            #[cfg(feature = "websocket")]
+            ws @ Stream::Ws(..) => {
+                // websockets keep protocol state, so both halves use the same one
+                let shared = Arc::new(Mutex::new(ws));
+                Ok((ReadHalf::Shared(shared.clone()), shared))
+            }
+        }
+    }
+
+    /// Shutdown both halves of the stream.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream, _) => stream.shutdown(Shutdown::Both),
+            #[cfg(feature = "websocket")]
+            Stream::Ws(ws, _) => ws::shutdown(ws),
+        }
+    }
+}
+
+impl ReadHalf {
+    /// Receive a message (blocking).
+    pub fn recv<M>(&mut self) -> Result<RecvResult<(Header, M)>, Error>
+    where
+        M: DeserializeOwned
+    {
+        match self {
+            ReadHalf::Owned(stream) => match stream.recv(None) {
+                Ok(msg) => Ok(RecvResult::Some(msg)),
+                Err(Error::Closed) => Ok(RecvResult::Closed),
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Ok(RecvResult::ClosedWrongly)
+                }
+                Err(e) => Err(e),
+            },
+            #[cfg(feature = "websocket")]
+            ReadHalf::Shared(stream) => loop {
+                // never wait while holding the stream, so writers cannot starve
+                let res = stream.lock().unwrap().try_recv_sized().0?;
+                match res {
+                    RecvResult::None => thread::sleep(SHARED_POLL),
+                    res => return Ok(res),
+                }
+            },
+        }
+    }
+}
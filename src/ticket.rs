@@ -0,0 +1,172 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Requests waiting for their reply, shared by the handles and the background thread.
+pub struct InFlight<M> {
+    /// Id for the next request.
+    next_id: AtomicU64,
+    /// Slots of the waiting requests by id, `None` once the connection is gone.
+    waiting: Mutex<Option<HashMap<u64, Arc<Slot<M>>>>>,
+}
+
+/// Where the reply to a single request is left.
+struct Slot<M> {
+    state: Mutex<SlotState<M>>,
+    ready: Condvar,
+}
+
+struct SlotState<M> {
+    /// The reply, until it is taken.
+    reply: Option<Result<M, Error>>,
+    /// Set once the reply was taken.
+    taken: bool,
+    /// Task to wake when polled as a future.
+    waker: Option<Waker>,
+}
+
+/// Pending reply to a request sent with `ClientHandle::request`.
+///
+/// The reply can be waited on (blocking), polled, or awaited as a future. Dropping the
+/// ticket forgets about the request, its reply is discarded when it arrives.
+pub struct Ticket<M> {
+    /// Id of the request.
+    id: u64,
+    slot: Arc<Slot<M>>,
+    in_flight: Arc<InFlight<M>>,
+}
+
+impl<M> InFlight<M> {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            waiting: Mutex::new(Some(HashMap::new())),
+        }
+    }
+
+    /// Register a new request, fails once the connection is gone.
+    pub fn register(self: &Arc<Self>) -> Result<Ticket<M>, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let slot = Arc::new(Slot {
+            state: Mutex::new(SlotState { reply: None, taken: false, waker: None }),
+            ready: Condvar::new(),
+        });
+        match self.waiting.lock().unwrap().as_mut() {
+            Some(waiting) => waiting.insert(id, slot.clone()),
+            None => return Err(Error::Closed),
+        };
+        Ok(Ticket { id, slot, in_flight: self.clone() })
+    }
+
+    /// Hand a reply to the request waiting for it, returns false if none is.
+    pub fn resolve(&self, id: u64, reply: M) -> bool {
+        let slot = self.waiting.lock().unwrap().as_mut().and_then(|waiting| waiting.remove(&id));
+        match slot {
+            Some(slot) => {
+                slot.fill(Ok(reply));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fail every waiting request, and any later one, once the connection is gone.
+    pub fn close(&self) {
+        let waiting = self.waiting.lock().unwrap().take();
+        for (_, slot) in waiting.into_iter().flatten() {
+            slot.fill(Err(Error::Closed));
+        }
+    }
+}
+
+impl<M> Slot<M> {
+    fn fill(&self, reply: Result<M, Error>) {
+        let mut state = self.state.lock().unwrap();
+        state.reply = Some(reply);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+impl<M> SlotState<M> {
+    /// Take the reply if it arrived.
+    fn take(&mut self) -> Option<Result<M, Error>> {
+        if self.taken {
+            return Some(Err(Error::Other("reply already taken".to_string())));
+        }
+        let reply = self.reply.take();
+        self.taken = reply.is_some();
+        reply
+    }
+}
+
+impl<M> Ticket<M> {
+    /// Id of the request.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait for the reply (blocks).
+    ///
+    /// Fails with `Error::Closed` if the connection is gone before the reply arrives.
+    pub fn wait(self) -> Result<M, Error> {
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(reply) = state.take() {
+                return reply;
+            }
+            state = self.slot.ready.wait(state).unwrap();
+        }
+    }
+
+    /// Wait for the reply, at most for the given duration, failing with `Error::Timeout`.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<M, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if let Some(reply) = state.take() {
+                return reply;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(Error::Timeout);
+            }
+            state = self.slot.ready.wait_timeout(state, remaining).unwrap().0;
+        }
+    }
+
+    /// Get the reply if it already arrived, returns immediately.
+    pub fn try_wait(&mut self) -> Result<Option<M>, Error> {
+        self.slot.state.lock().unwrap().take().transpose()
+    }
+}
+
+impl<M> Future for Ticket<M> {
+    type Output = Result<M, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.slot.state.lock().unwrap();
+        match state.take() {
+            Some(reply) => Poll::Ready(reply),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<M> Drop for Ticket<M> {
+    fn drop(&mut self) {
+        if let Some(waiting) = self.in_flight.waiting.lock().unwrap().as_mut() {
+            waiting.remove(&self.id);
+        }
+    }
+}
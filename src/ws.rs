@@ -0,0 +1,264 @@
+//! WebSocket transport, each binary message carries a single frame.
+use crate::error::Error;
+use crate::pk::{self, Header, RecvResult};
+use serde::Serialize;
+#[cfg(feature = "json")]
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::error::ProtocolError;
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::{Message, WebSocket};
+
+/// How long a client has to complete its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Encoding used for messages sent over a WebSocket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Binary messages, each carrying one length prefixed frame (same as tcp).
+    Bincode,
+    /// Text messages, each carrying one JSON encoded message.
+    #[cfg(feature = "json")]
+    Json,
+}
+
+/// Request of a websocket handshake, as seen by the server.
+pub(crate) struct Handshake {
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Handshake {
+    /// Value of a header, by its case insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Perform the server side of the handshake on a freshly accepted stream (blocking).
+// the error of the handshake callback is chosen by tungstenite
+#[allow(clippy::result_large_err)]
+pub fn accept(stream: TcpStream) -> Result<(Box<WebSocket<TcpStream>>, Handshake), Error> {
+    stream.set_nonblocking(false)?;
+    // a client that never completes the handshake would hold its thread forever otherwise
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut handshake = None;
+    let ws = tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+        handshake = Some(Handshake {
+            path: request.uri().path().to_string(),
+            headers: request.headers().iter()
+                .filter_map(|(key, value)| Some((key.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+        });
+        Ok(response)
+    }).map_err(|e| Error::Other(e.to_string()))?;
+    ws.get_ref().set_read_timeout(None)?;
+    // the callback always runs before the handshake succeeds
+    Ok((Box::new(ws), handshake.unwrap()))
+}
+
+/// Connect to a WebSocket server by its url, e.g. `ws://127.0.0.1:6936`, opening the
+/// tcp stream to its host and port with the given function.
+pub fn connect<F>(url: &str, open: F) -> Result<Box<WebSocket<TcpStream>>, Error>
+where
+    F: FnOnce((&str, u16)) -> Result<TcpStream, Error>
+{
+    let request = url.into_client_request()?;
+    // there is no tls support, which tungstenite would not tell us about
+    let default_port = match request.uri().scheme_str() {
+        Some("ws") => 80,
+        _ => return Err(Error::Other(format!("unsupported websocket url, only ws:// is: {}", url))),
+    };
+    let host = request.uri().host()
+        .ok_or_else(|| Error::Other(format!("websocket url has no host: {}", url)))?;
+    let port = request.uri().port_u16().unwrap_or(default_port);
+    let stream = open((host, port))?;
+    let (ws, _) = tungstenite::client(request, stream)
+        .map_err(|e| Error::Other(e.to_string()))?;
+    Ok(Box::new(ws))
+}
+
+/// JSON encoded message, the header fields may be left out.
+#[cfg(feature = "json")]
+#[derive(Serialize, Deserialize)]
+struct JsonMessage<M> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<u64>,
+    msg: M,
+}
+
+/// Encode a message for a websocket, failing if its payload is larger than the given size.
+pub fn encode<M>(header: Header, msg: M, codec: Codec, max: Option<u64>) -> Result<Message, Error>
+where
+    M: Serialize
+{
+    let msg = match codec {
+        Codec::Bincode => Message::Binary(pk::encode_frame(header, msg)?),
+        #[cfg(feature = "json")]
+        Codec::Json => Message::Text(serde_json::to_string(&JsonMessage {
+            id: header.id,
+            reply_to: header.reply_to,
+            trace: header.trace,
+            msg,
+        })?),
+    };
+    pk::check_size(msg.len() as u64, max)?;
+    Ok(msg)
+}
+
+/// Send an encoded message via a websocket (blocking), returns the size of its payload.
+pub fn write(msg: Message, ws: &mut WebSocket<TcpStream>) -> Result<usize, Error> {
+    ws.get_mut().set_nonblocking(false)?;
+    let len = msg.len();
+    ws.send(msg)?;
+    Ok(len)
+}
+
+/// Attempts to receive a message from the websocket (blocking).
+///
+/// With a timeout, fails with `Error::Timeout` if no message arrived in time.
+pub fn recv<M>(ws: &mut WebSocket<TcpStream>, codec: Codec, timeout: Option<Duration>)
+    -> Result<(Header, M), Error>
+where
+    M: DeserializeOwned
+{
+    if let Some(timeout) = timeout {
+        return recv_timeout(ws, codec, timeout)?.into_result();
+    }
+    ws.get_mut().set_nonblocking(false)?;
+    ws.get_mut().set_read_timeout(None)?;
+    loop {
+        if let Some(msg) = decode(ws.read()?, codec)? {
+            return Ok(msg);
+        }
+    }
+}
+
+/// Return immediately if there are no incoming messages.
+///
+/// Partially received messages are kept by the websocket until they are complete.
+pub fn try_recv<M>(ws: &mut WebSocket<TcpStream>, codec: Codec)
+    -> Result<RecvResult<(Header, M)>, Error>
+where
+    M: DeserializeOwned
+{
+    ws.get_mut().set_nonblocking(true)?;
+    read(ws, codec)
+}
+
+/// Same as `try_recv`, also giving the size of the payload of the message read, if any.
+pub fn try_recv_sized<M>(ws: &mut WebSocket<TcpStream>, codec: Codec)
+    -> (Result<RecvResult<(Header, M)>, Error>, usize)
+where
+    M: DeserializeOwned
+{
+    if let Err(e) = ws.get_mut().set_nonblocking(true) {
+        return (Err(e.into()), 0);
+    }
+    read_sized(ws, codec)
+}
+
+/// Block until a message arrives, or the timeout elapses.
+pub fn recv_timeout<M>(ws: &mut WebSocket<TcpStream>, codec: Codec, timeout: Duration)
+    -> Result<RecvResult<(Header, M)>, Error>
+where
+    M: DeserializeOwned
+{
+    ws.get_mut().set_nonblocking(false)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::ZERO {
+            return try_recv(ws, codec);
+        }
+        ws.get_mut().set_read_timeout(Some(remaining))?;
+        match read(ws, codec)? {
+            RecvResult::None => continue,
+            res => return Ok(res),
+        }
+    }
+}
+
+/// Read the next message, control messages and reads that would block yield `None`.
+fn read<M>(ws: &mut WebSocket<TcpStream>, codec: Codec) -> Result<RecvResult<(Header, M)>, Error>
+where
+    M: DeserializeOwned
+{
+    read_sized(ws, codec).0
+}
+
+/// Same as `read`, also giving the size of the payload of the message read, if any.
+fn read_sized<M>(ws: &mut WebSocket<TcpStream>, codec: Codec)
+    -> (Result<RecvResult<(Header, M)>, Error>, usize)
+where
+    M: DeserializeOwned
+{
+    let res = match ws.read() {
+        Ok(Message::Close(_)) => Ok(RecvResult::Closed),
+        Ok(msg) => {
+            let len = msg.len();
+            let res = decode(msg, codec).map(|msg| match msg {
+                Some(msg) => RecvResult::Some(msg),
+                None => RecvResult::None,
+            });
+            return (res, len);
+        }
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::WouldBlock
+            || e.kind() == ErrorKind::TimedOut => {
+            Ok(RecvResult::None)
+        }
+        Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+            Ok(RecvResult::Closed)
+        }
+        Err(tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => {
+            Ok(RecvResult::ClosedWrongly)
+        }
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof
+            || e.kind() == ErrorKind::ConnectionReset => {
+            Ok(RecvResult::ClosedWrongly)
+        }
+        Err(e) => Err(e.into()),
+    };
+    (res, 0)
+}
+
+/// Send a close frame and shut the underlying stream down.
+pub fn shutdown(ws: &mut WebSocket<TcpStream>) -> std::io::Result<()> {
+    ws.get_mut().set_nonblocking(false)?;
+    // the peer may already be gone, we only care about the tcp shutdown
+    let _ = ws.close(None);
+    let _ = ws.flush();
+    ws.get_mut().shutdown(std::net::Shutdown::Both)
+}
+
+/// Decode a data message, control messages yield `None`.
+fn decode<M>(msg: Message, codec: Codec) -> Result<Option<(Header, M)>, Error>
+where
+    M: DeserializeOwned
+{
+    match msg {
+        Message::Binary(data) if codec == Codec::Bincode => {
+            Ok(Some(pk::decode_frame(data.as_slice())?))
+        }
+        #[cfg(feature = "json")]
+        Message::Text(text) if codec == Codec::Json => {
+            let msg: JsonMessage<M> = serde_json::from_str(text.as_str())?;
+            Ok(Some((Header { id: msg.id, reply_to: msg.reply_to, trace: msg.trace }, msg.msg)))
+        }
+        Message::Binary(_) | Message::Text(_) => {
+            Err(Error::Codec(format!("unexpected websocket message for codec {:?}", codec).into()))
+        }
+        Message::Close(_) => Err(Error::Closed),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,348 @@
+//! Procedural macros for `srve`, use them through the `derive` feature of `srve`.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Error, Fields, FnArg, Ident, ItemTrait,
+    Pat, ReturnType, TraitItem, Type,
+};
+
+/// Generate the messages, a typed client and a server dispatcher from a trait.
+///
+/// For a trait `Calc`, with methods taking `&self` or `&mut self`, this generates:
+///
+/// - `CalcRequest`, with a variant per method holding its arguments.
+/// - `CalcResponse`, with a variant per method holding its return value.
+/// - `CalcClient`, a client with the same methods, each sending a request and waiting for
+///   its reply.
+/// - `calc_dispatch`, to use with `Server::on_request`, running the method on the state of
+///   the connection (which implements `Calc`) and replying with its return value.
+///
+/// ```ignore
+/// #[srve::service]
+/// pub trait Calc {
+///     fn add(&mut self, x: i32);
+///     fn print(&self) -> i32;
+/// }
+///
+/// Server::<State, CalcRequest, CalcResponse>::bind(addr)?
+///     .on_request(calc_dispatch::<State>)
+///     .run();
+///
+/// let mut c = CalcClient::connect(addr)?;
+/// c.add(2)?;
+/// assert_eq!(c.print()?, 2);
+/// ```
+#[proc_macro_attribute]
+pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = TokenStream2::from(attr);
+        return Error::new(attr.span(), "`service` takes no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemTrait);
+    match expand(item) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Implement `srve::Discriminant` for an enum.
+///
+/// For an enum `Msg`, this generates a `MsgKind` enum with the same variants, without their
+/// fields, to route messages by their variant with a `srve::Router`.
+#[proc_macro_derive(Discriminant)]
+pub fn derive_discriminant(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_discriminant(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_discriminant(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new(input.ident.span(), "`Discriminant` only applies to enums")),
+    };
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let kind = format_ident!("{}Kind", name);
+    let kind_doc = format!("Variants of `{}`, without their fields.", name);
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let variants: Vec<_> = data.variants.iter().map(|v| &v.ident).collect();
+    let arms = data.variants.iter().map(|v| {
+        let variant = &v.ident;
+        let fields = match &v.fields {
+            Fields::Named(_) => quote! { { .. } },
+            Fields::Unnamed(_) => quote! { (..) },
+            Fields::Unit => quote! {},
+        };
+        quote! { #name::#variant #fields => #kind::#variant, }
+    });
+
+    Ok(quote! {
+        #[doc = #kind_doc]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #vis enum #kind {
+            #(#variants,)*
+        }
+
+        impl #impl_generics ::srve::Discriminant for #name #ty_generics #where_clause {
+            type Kind = #kind;
+
+            fn kind(&self) -> #kind {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+/// A method of the service trait.
+struct Method {
+    /// Name of the method.
+    name: Ident,
+    /// Name of its variant in the generated enums.
+    variant: Ident,
+    /// Documentation of the method, kept for the client.
+    docs: Vec<Attribute>,
+    /// Whether it takes `&mut self`.
+    mutable: bool,
+    /// Name and type of each argument.
+    args: Vec<(Ident, Type)>,
+    /// Return type.
+    output: Type,
+}
+
+fn expand(item: ItemTrait) -> Result<TokenStream2, Error> {
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(item.generics.span(), "services cannot be generic"));
+    }
+    let methods = item.items.iter()
+        .filter_map(|item| match item {
+            TraitItem::Fn(method) => Some(parse_method(method)),
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let vis = &item.vis;
+    let service = &item.ident;
+    let request = format_ident!("{}Request", service);
+    let response = format_ident!("{}Response", service);
+    let client = format_ident!("{}Client", service);
+    let dispatch = format_ident!("{}_dispatch", snake_case(&service.to_string()));
+
+    let request_doc = format!("Requests of the `{}` service, one per method.", service);
+    let response_doc = format!("Replies of the `{}` service, one per method.", service);
+    let client_doc = format!("Client of the `{}` service, over a `srve::Client`.", service);
+    let dispatch_doc = format!(
+        "Answer a `{}` request with the state of the connection, use with `Server::on_request`.",
+        service,
+    );
+
+    let request_variants = methods.iter().map(|m| {
+        let variant = &m.variant;
+        let types = m.args.iter().map(|(_, ty)| ty);
+        if m.args.is_empty() {
+            quote! { #variant }
+        } else {
+            quote! { #variant(#(#types),*) }
+        }
+    });
+    let response_variants = methods.iter().map(|m| {
+        let variant = &m.variant;
+        let output = &m.output;
+        quote! { #variant(#output) }
+    });
+
+    let client_methods = methods.iter().map(|m| {
+        let Method { name, variant, docs, args, output, .. } = m;
+        let params = args.iter().map(|(name, ty)| quote! { #name: #ty });
+        let names = args.iter().map(|(name, _)| name);
+        let msg = if args.is_empty() {
+            quote! { #request::#variant }
+        } else {
+            quote! { #request::#variant(#(#names),*) }
+        };
+        let unexpected = format!("unexpected reply to `{}`: {{:?}}", name);
+        quote! {
+            #(#docs)*
+            #[allow(unreachable_patterns)]
+            pub fn #name(&mut self, #(#params),*) -> ::std::result::Result<#output, ::srve::Error> {
+                match self.client.request(#msg)? {
+                    #response::#variant(ret) => ::std::result::Result::Ok(ret),
+                    other => ::std::result::Result::Err(
+                        ::srve::Error::Other(::std::format!(#unexpected, other))
+                    ),
+                }
+            }
+        }
+    });
+
+    let dispatch_arms = methods.iter().map(|m| {
+        let Method { name, variant, mutable, args, .. } = m;
+        let vars: Vec<_> = (0..args.len()).map(|i| format_ident!("arg{}", i)).collect();
+        let pattern = if args.is_empty() {
+            quote! { #request::#variant }
+        } else {
+            quote! { #request::#variant(#(#vars),*) }
+        };
+        let state = if *mutable {
+            quote! { &mut **conn }
+        } else {
+            quote! { &**conn }
+        };
+        quote! {
+            #pattern => #response::#variant(<S as #service>::#name(#state, #(#vars),*)),
+        }
+    });
+
+    Ok(quote! {
+        #item
+
+        #[doc = #request_doc]
+        #[derive(::srve::__private::serde::Serialize, ::srve::__private::serde::Deserialize, Debug)]
+        #[serde(crate = "::srve::__private::serde")]
+        #vis enum #request {
+            #(#request_variants,)*
+        }
+
+        #[doc = #response_doc]
+        #[derive(::srve::__private::serde::Serialize, ::srve::__private::serde::Deserialize, Debug)]
+        #[serde(crate = "::srve::__private::serde")]
+        #vis enum #response {
+            #(#response_variants,)*
+        }
+
+        #[doc = #client_doc]
+        #vis struct #client {
+            client: ::srve::Client<#request, #response>,
+        }
+
+        impl #client {
+            /// Connect to a server by its address.
+            pub fn connect<A>(addr: A) -> ::std::result::Result<Self, ::srve::Error>
+            where
+                A: ::std::net::ToSocketAddrs,
+            {
+                ::std::result::Result::Ok(Self::from_client(::srve::Client::connect(addr)?))
+            }
+
+            /// Use an already connected client, e.g. one built with a `ClientBuilder`.
+            pub fn from_client(client: ::srve::Client<#request, #response>) -> Self {
+                Self { client }
+            }
+
+            /// Give up the underlying client.
+            pub fn into_inner(self) -> ::srve::Client<#request, #response> {
+                self.client
+            }
+
+            /// Closes the connection to the server.
+            pub fn close(self) -> ::std::result::Result<(), ::srve::Error> {
+                self.client.close()
+            }
+
+            #(#client_methods)*
+        }
+
+        #[doc = #dispatch_doc]
+        #vis fn #dispatch<S>(
+            conn: &mut ::srve::Conn<S, #response>,
+            request: ::srve::Request,
+            msg: #request,
+        )
+        where
+            S: #service,
+        {
+            let reply = match msg {
+                #(#dispatch_arms)*
+            };
+            let _ = conn.reply(&request, reply);
+        }
+    })
+}
+
+fn parse_method(method: &syn::TraitItemFn) -> Result<Method, Error> {
+    let sig = &method.sig;
+    if !sig.generics.params.is_empty() {
+        return Err(Error::new(sig.generics.span(), "service methods cannot be generic"));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new(asyncness.span(), "service methods cannot be async"));
+    }
+    let mutable = match sig.receiver() {
+        Some(receiver) if receiver.reference.is_some() => receiver.mutability.is_some(),
+        _ => {
+            return Err(Error::new(
+                sig.span(),
+                "service methods must take `&self` or `&mut self`",
+            ))
+        }
+    };
+
+    let mut args = Vec::new();
+    for input in sig.inputs.iter().skip(1) {
+        match input {
+            FnArg::Typed(arg) => match &*arg.pat {
+                Pat::Ident(pat) => args.push((pat.ident.clone(), (*arg.ty).clone())),
+                pat => return Err(Error::new(pat.span(), "expected an argument name")),
+            },
+            FnArg::Receiver(receiver) => {
+                return Err(Error::new(receiver.span(), "unexpected receiver"));
+            }
+        }
+    }
+
+    let output = match &sig.output {
+        ReturnType::Default => syn::parse_quote! { () },
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+
+    Ok(Method {
+        name: sig.ident.clone(),
+        variant: Ident::new(&camel_case(&sig.ident.to_string()), Span::call_site()),
+        docs: method.attrs.iter().filter(|a| a.path().is_ident("doc")).cloned().collect(),
+        mutable,
+        args,
+        output,
+    })
+}
+
+/// `add_all` -> `AddAll`
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// `MyService` -> `my_service`
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}